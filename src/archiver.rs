@@ -1,180 +1,984 @@
 use std::env;
+use std::fmt;
 use std::mem::size_of; // For shortening size_of::<>() functions
 use std::fs::File; // For files
 use std::io::SeekFrom;
 use std::io::prelude::*; // For writing into vecs
-use std::path::Path; // For navigating filesystem
+use std::path::{Component, Path}; // For navigating filesystem
 use std::path::PathBuf;
-use std::collections::HashMap; // For archive tags
+use std::collections::{HashMap, HashSet}; // For archive tags
+use std::rc::Rc; // For sharing an archive's reader between entries
+use std::cell::RefCell;
 
 use std::convert::TryInto; // For fitting known size slices into arrays
 
+pub mod codec;
+pub mod metadata;
+pub mod pattern;
+pub mod sandbox;
+pub mod transform;
+use self::codec::{Codec, Store};
+use self::metadata::{Metadata, Timestamp};
+use self::pattern::{IgnoreRules, PathFilter};
+use self::sandbox::Sandbox;
+use self::transform::{Chain, PlainEncoder, Transform};
+
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const ARCHIVE_VERSION: u8 = 9; // Note: 0 is reserved for generic unsupported, in case versions go over 255 (they won't)
+const SUPPORTED_ARCHIVE_VERSIONS: [u8; 9] = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+/// Written at the very start of archives since version 8, before the version byte.
+/// The first byte can't be mistaken for the version byte of an older archive, and isn't ASCII
+pub const MAGIC: [u8; 4] = *b"\x89MPK";
+const FIRST_MAGIC_VERSION: u8 = 8; // Archives before this version start straight with their version byte
+
+// Since version 9 the header can be written at the end of the archive instead, as an index, so archives can be
+// written in a single pass. These start with a prefix of the magic number, the version and a header size of 0,
+// then the entries' data, then the index (laid out exactly like a header), then a footer that points back to it:
+// the index's offset from the start of the archive (u64), its size (u64), then FOOTER_MAGIC
+const FIRST_TRAILING_VERSION: u8 = 9;
+pub const TRAILING_PREFIX_SIZE: u64 = 13;
+pub const FOOTER_SIZE: u64 = 20;
+/// Ends every archive with a trailing index, so the footer can be found from the end of a file
+pub const FOOTER_MAGIC: [u8; 4] = *b"MPK\x89";
+
+// Entry flags, stored in a byte for each entry since version 4
+const ENTRY_HAS_CHECKSUM: u8 = 1; // The entry's checksum field holds a real checksum
+const ENTRY_HAS_METADATA: u8 = 2; // The entry's metadata follows its checksum, since version 5
+const ENTRY_CHANGED: u8 = 4; // The file changed while it was being packed, so its data might be a mix of before and after
+
+// Entry kinds, stored in a byte for each entry since version 6
+const KIND_FILE: u8 = 0;
+const KIND_SYMLINK: u8 = 1; // Followed by the link's target
+const KIND_HARDLINK: u8 = 2; // Followed by the path of the entry it links to
+const KIND_DIRECTORY: u8 = 3; // Since version 7
+
+const COPY_BUFFER_SIZE: usize = 256 * 1024; // Entry data is copied through a buffer this big, however big the entry is
+
+// Tags that record where an archive came from. The archiver fills these in itself, and names starting with
+// RESERVED_TAG_PREFIX are kept for it
+pub const RESERVED_TAG_PREFIX: &str = "mpk.";
+pub const TAG_WRITER: &str = "mpk.writer"; // The name of the program that packed the archive
+pub const TAG_WRITER_VERSION: &str = "mpk.writer_version";
+pub const TAG_CREATED: &str = "mpk.created"; // When the archive was packed, as an RFC 3339 UTC timestamp
+pub const TAG_HOST: &str = "mpk.host"; // Only recorded when asked for, see ArchiveBuilder::record_host
+pub const TAG_COMMAND: &str = "mpk.command"; // Only recorded when asked for, see ArchiveBuilder::record_command
+
+// Limits on what a header can hold, so a malicious archive can't make the reader allocate or loop without bound.
+// The builder refuses to write anything past them too, so every archive it makes can be read back
+pub const MAX_HEADER_SIZE: u64 = 256 * 1024 * 1024;
+pub const MAX_TAGS: u64 = 64 * 1024;
+pub const MAX_ENTRIES: u64 = 16 * 1024 * 1024;
+pub const MAX_PATH_LENGTH: usize = 4096; // In bytes, for entry paths and link targets
+
+// Free space left after the header of new archives, so adding entries or tags later doesn't have to move the data.
+// When an edit doesn't fit, the data is moved along by this much more than it needs so the next edits will
+pub const HEADER_RESERVE: u64 = 4096;
+
+
+/// Everything that can go wrong while reading or writing an archive
+#[derive(Debug)]
+pub enum MpkError {
+	/// An underlying read or write failed
+	Io(std::io::Error),
+	/// The header ended before all of its fields could be read
+	TruncatedHeader,
+	/// The archive was packed with a format version this archiver can't read
+	UnsupportedVersion(u8),
+	/// The file doesn't start like any archive this archiver knows about
+	NotAnArchive,
+	/// A path couldn't be converted to or from UTF-8
+	InvalidUtf8Path(PathBuf),
+	/// A tag's name or contents couldn't be read, holds the (lossy) tag name
+	MalformedTag(String),
+	/// An entry's path would place it outside of the directory it's extracted to
+	PathEscape(PathBuf),
+	/// An entry was encoded with a codec this archiver doesn't know about
+	UnknownCodec(u8),
+	/// An entry's data doesn't match the checksum it was packed with
+	ChecksumMismatch(PathBuf),
+	/// The header doesn't match its checksum, so none of it can be trusted
+	HeaderChecksumMismatch,
+	/// An entry is of a kind this archiver doesn't know about
+	UnknownEntryKind(u8),
+	/// A hard link points to an entry that isn't an earlier file in the archive
+	MissingLinkTarget(PathBuf),
+	/// The header is bigger than [`MAX_HEADER_SIZE`], holds the size it claims to be
+	HeaderTooLarge(u64),
+	/// The header has more than [`MAX_TAGS`] tags, holds how many it claims to have
+	TooManyTags(u64),
+	/// The header has more than [`MAX_ENTRIES`] entries, holds how many it claims to have
+	TooManyEntries(u64),
+	/// An entry's path or link target is longer than [`MAX_PATH_LENGTH`], holds its length
+	PathTooLong(u64),
+	/// An entry's data lies past the end of the archive
+	EntryOutOfBounds(PathBuf),
+	/// A glob pattern couldn't be parsed, holds the pattern
+	InvalidPattern(String),
+	/// An entry being added has the same path as one already in the archive
+	DuplicateEntry(PathBuf),
+	/// The archive's index is at its end, so it can't be read without seeking, see [`read_index`]
+	TrailingIndex,
+	/// A file changed while it was being packed, with [`ChangePolicy::Fail`]
+	FileChanged(PathBuf),
+	/// A directory links back to one containing it, so following it would go around forever
+	LinkLoop(PathBuf),
+	/// A link being followed points to something that doesn't exist
+	BrokenLink(PathBuf),
+	/// A path being packed couldn't be looked at, holds why
+	UnreadablePath(PathBuf, std::io::Error),
+}
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const ARCHIVE_VERSION: u8 = 1; // Note: 0 is reserved for generic unsupported, in case versions go over 255 (they won't)
-const SUPPORTED_ARCHIVE_VERSIONS: [u8; 1] = [1];
-// const COMPRESSION_VERSION: u8 = 1;
-// const SUPPORTED_COMPRESSION_VERSIONS: [u8; 1] = [1];
+impl fmt::Display for MpkError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MpkError::Io(why) => write!(f, "{}", why),
+			MpkError::TruncatedHeader => write!(f, "The archive header ended unexpectedly"),
+			MpkError::UnsupportedVersion(version) => write!(f,
+				"This version of the archiver ({}) does not support this archive's version ({}).\nTry updating to the latest version, your current version is {}",
+				ARCHIVE_VERSION, version, VERSION),
+			MpkError::NotAnArchive => write!(f, "This isn't a micropak archive, or it's been badly corrupted"),
+			MpkError::InvalidUtf8Path(path) => write!(f, "Couldn't convert path \"{}\" to a string, maybe it isn't UTF-8?", path.display()),
+			MpkError::MalformedTag(name) => write!(f, "Tag \"{}\" is malformed", name),
+			MpkError::PathEscape(path) => write!(f, "Path \"{}\" leads outside of the extraction directory", path.display()),
+			MpkError::UnknownCodec(id) => write!(f, "Unknown codec {}, the archive may have been packed by a newer or custom archiver", id),
+			MpkError::ChecksumMismatch(path) => write!(f, "The data of \"{}\" is corrupt, it doesn't match its checksum", path.display()),
+			MpkError::HeaderChecksumMismatch => write!(f, "The archive header is corrupt, it doesn't match its checksum"),
+			MpkError::UnknownEntryKind(kind) => write!(f, "Unknown entry kind {}, the archive may have been packed by a newer archiver", kind),
+			MpkError::MissingLinkTarget(path) => write!(f, "Hard link target \"{}\" isn't a file in the archive", path.display()),
+			MpkError::HeaderTooLarge(size) => write!(f, "The archive header is {} bytes, more than the limit of {}", size, MAX_HEADER_SIZE),
+			MpkError::TooManyTags(count) => write!(f, "The archive has {} tags, more than the limit of {}", count, MAX_TAGS),
+			MpkError::TooManyEntries(count) => write!(f, "The archive has {} entries, more than the limit of {}", count, MAX_ENTRIES),
+			MpkError::PathTooLong(len) => write!(f, "An entry path is {} bytes long, more than the limit of {}", len, MAX_PATH_LENGTH),
+			MpkError::EntryOutOfBounds(path) => write!(f, "The data of \"{}\" lies past the end of the archive", path.display()),
+			MpkError::InvalidPattern(pattern) => write!(f, "\"{}\" isn't a valid pattern", pattern),
+			MpkError::DuplicateEntry(path) => write!(f, "\"{}\" is already in the archive", path.display()),
+			MpkError::TrailingIndex => write!(f, "The archive's index is at its end, so it can only be read from a file"),
+			MpkError::FileChanged(path) => write!(f, "\"{}\" changed while it was being packed", path.display()),
+			MpkError::LinkLoop(path) => write!(f, "\"{}\" links back to a directory containing it", path.display()),
+			MpkError::BrokenLink(path) => write!(f, "\"{}\" is a broken link", path.display()),
+			MpkError::UnreadablePath(path, why) => write!(f, "Couldn't read \"{}\": {}", path.display(), why),
+		}
+	}
+}
 
-const MAX_BUFFER_SIZE: usize = 2_000_000_000; // Max buffer size is 2 GB
+impl std::error::Error for MpkError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			MpkError::Io(why) | MpkError::UnreadablePath(_, why) => Some(why),
+			_ => None
+		}
+	}
+}
 
+impl From<std::io::Error> for MpkError {
+	fn from(why: std::io::Error) -> MpkError {
+		MpkError::Io(why)
+	}
+}
 
-pub struct Archive {
-	pub file: File,
+/// An archive opened for reading, backed by anything that can [`Read`] and [`Seek`]
+pub struct Archive<R> {
+	pub file: R,
 	pub header: Header,
+	start: u64, // Where the archive begins in `file`, so archives can be embedded in other data
+	codecs: Vec<Rc<dyn Codec>>, // Codecs that entries can be decoded with
+	transform: Option<Rc<dyn Transform>> // Undone on every entry's data before its codec, see ArchiveBuilder::add_transform
+}
+
+impl<R: Read + Seek> Archive<R> {
+	/// Reads the header of the archive starting at the current position of `file`
+	pub fn new(mut file: R) -> Result<Archive<R>, MpkError> {
+		let start = file.stream_position()?;
+		let header = read_index(&mut file)?;
+
+		// Make sure every entry's data is inside the archive, so reading it can't run off somewhere else
+		let header_end = file.stream_position()?;
+		let len = file.seek(SeekFrom::End(0))?.saturating_sub(start);
+		file.seek(SeekFrom::Start(header_end))?;
+		for entry in &header.entries {
+			match entry.offset.checked_add(entry.stored_size) {
+				Some(end) if end <= len => (),
+				_ => return Err(MpkError::EntryOutOfBounds(entry.path.clone()))
+			}
+		}
+
+		let codecs = codec::builtin_codecs().into_iter().map(Rc::from).collect();
+		Ok(Archive { file, header, start, codecs, transform: None })
+	}
+
+	/// Lets entries encoded with `codec` be read, replacing any codec with the same id
+	pub fn add_codec(&mut self, codec: Box<dyn Codec>) {
+		self.codecs.retain(|c| c.id() != codec.id());
+		self.codecs.push(Rc::from(codec));
+	}
+
+	/// Sets the transform that the archive's entries were packed with, so it can be undone when reading them.
+	/// Unlike codecs transforms aren't recorded in the archive, so this has to match what was given to
+	/// [`ArchiveBuilder::add_transform`]
+	pub fn set_transform(&mut self, transform: Box<dyn Transform>) {
+		self.transform = Some(Rc::from(transform));
+	}
+
+	/// Returns the transforms that undo the encoding of `entry`, in the order they were applied
+	fn entry_transforms(&self, entry: &FileEntry) -> Result<Vec<Rc<dyn Transform>>, MpkError> {
+		let mut transforms: Vec<Rc<dyn Transform>> = vec![find_codec(&self.codecs, entry.codec)?];
+		transforms.extend(self.transform.clone());
+		Ok(transforms)
+	}
+
+	/// Consumes the archive, giving back the underlying reader
+	pub fn into_inner(self) -> R {
+		self.file
+	}
+
+	/// Returns an iterator over the archive's entries, each of which can be read on its own
+	///
+	/// # Examples
+	///
+	/// ```ignore
+	/// for mut entry in archive.entries() {
+	///     let mut contents = Vec::new();
+	///     entry.read_to_end(&mut contents)?;
+	///     println!("{}: {} bytes", entry.entry.path.display(), contents.len());
+	/// }
+	/// ```
+	pub fn entries(&mut self) -> Entries<'_, R> {
+		Entries {
+			file: Rc::new(RefCell::new(&mut self.file)),
+			entries: self.header.entries.iter(),
+			start: self.start,
+			codecs: &self.codecs,
+			transform: self.transform.clone()
+		}
+	}
+
+	/// Reads the header of the archive starting at the current position of `file`, without looking any further ahead.
+	/// This is for readers that can only go forwards, like a [`SequentialReader`], where entries have to be read in
+	/// the order their data comes in. [`extract_entries`] and [`verify_archive`] already do that.
+	/// Archives with a trailing index can't be read this way, and give [`MpkError::TrailingIndex`]
+	///
+	/// # Examples
+	///
+	/// ```ignore
+	/// let mut archive = Archive::new_sequential(SequentialReader::new(std::io::stdin().lock()))?;
+	/// extract_all_archive(&mut archive, Path::new("out"), &ExtractOptions::default())?;
+	/// ```
+	pub fn new_sequential(mut file: R) -> Result<Archive<R>, MpkError> {
+		let start = file.stream_position()?;
+		let header = read_header(&mut file)?;
+		let codecs = codec::builtin_codecs().into_iter().map(Rc::from).collect();
+		Ok(Archive { file, header, start, codecs, transform: None })
+	}
+}
+
+/// Lets an archive be read from something that can't seek, like a pipe, see [`Archive::new_sequential`].
+/// Seeking forwards reads and throws away everything in between, while seeking backwards fails
+pub struct SequentialReader<R> {
+	inner: R,
+	position: u64 // How much has been read from `inner`
+}
+
+impl<R: Read> SequentialReader<R> {
+	pub fn new(inner: R) -> SequentialReader<R> {
+		SequentialReader { inner, position: 0 }
+	}
+}
+
+impl<R: Read> Read for SequentialReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let len = self.inner.read(buf)?;
+		self.position += len as u64;
+		Ok(len)
+	}
+}
+
+impl<R: Read> Seek for SequentialReader<R> {
+	fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+		let target = match position {
+			SeekFrom::Start(target) => Some(target),
+			SeekFrom::Current(offset) => offset_position(self.position, offset),
+			SeekFrom::End(_) => None
+		};
+		match target {
+			Some(target) if target >= self.position => {
+				let skip = target - self.position;
+				if std::io::copy(&mut self.by_ref().take(skip), &mut std::io::sink())? < skip {
+					return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The archive ended before the data being sought"));
+				}
+				Ok(self.position)
+			},
+			_ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Can't go backwards in an archive being read sequentially"))
+		}
+	}
+}
+
+/// Finds the codec with the id `id` in `codecs`
+fn find_codec(codecs: &[Rc<dyn Codec>], id: u8) -> Result<Rc<dyn Codec>, MpkError> {
+	codecs.iter().find(|codec| codec.id() == id).cloned().ok_or(MpkError::UnknownCodec(id))
+}
+
+/// An iterator over the entries of an [`Archive`], created by [`Archive::entries`]
+pub struct Entries<'a, R: 'a> {
+	file: Rc<RefCell<&'a mut R>>, // Shared between every EntryReader handed out
+	entries: std::slice::Iter<'a, FileEntry>,
+	start: u64,
+	codecs: &'a [Rc<dyn Codec>],
+	transform: Option<Rc<dyn Transform>>
+}
+
+impl<'a, R: Read + Seek> Iterator for Entries<'a, R> {
+	type Item = EntryReader<'a, R>;
+
+	fn next(&mut self) -> Option<EntryReader<'a, R>> {
+		let entry = self.entries.next()?;
+		Some(EntryReader {
+			entry,
+			raw: RawEntry { file: self.file.clone(), start: self.start + entry.offset, len: entry.stored_size, position: 0 },
+			codec: find_codec(self.codecs, entry.codec).ok(),
+			transform: self.transform.clone(),
+			decoder: None,
+			position: 0,
+			checksum: Some(0)
+		})
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.entries.size_hint()
+	}
+}
+
+/// Reads the data of a single entry in an archive, decoded with whichever codec it was packed with.
+/// Reads and seeks are limited to the entry's own bytes, so reading stops at the end of the entry
+/// rather than running into the next one.
+/// If the whole entry is read without seeking past any of it, the data is checked against the entry's checksum,
+/// and the read that reaches the end fails with `InvalidData` if it doesn't match
+pub struct EntryReader<'a, R: 'a> {
+	pub entry: &'a FileEntry,
+	raw: RawEntry<'a, R>, // The entry's data as it's stored in the archive
+	codec: Option<Rc<dyn Codec>>, // None if the codec is unknown
+	transform: Option<Rc<dyn Transform>>,
+	decoder: Option<Box<dyn Read + 'a>>, // Created on the first read of an entry that isn't stored as is
+	position: u64, // How far into the entry's decoded data we are
+	checksum: Option<u32> // Checksum of the data up to `position`, None once a seek skips over some of it
+}
+
+impl<'a, R: Read + Seek + 'a> EntryReader<'a, R> {
+	/// Whether the stored data is different to the decoded data, so has to go through a decoder
+	fn is_encoded(&self) -> bool {
+		match self.codec {
+			Some(ref codec) => codec.id() != Store.id() || self.transform.is_some(),
+			None => false
+		}
+	}
+}
+
+impl<'a, R: Read + Seek + 'a> Read for EntryReader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let codec = match self.codec {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, MpkError::UnknownCodec(self.entry.codec).to_string())),
+			Some(ref codec) => codec.clone()
+		};
+
+		let remaining = self.entry.size.saturating_sub(self.position);
+		let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+		if len == 0 {
+			return Ok(0);
+		}
+
+		let read = if !self.is_encoded() {
+			// Stored data can be read straight from wherever we are in the entry
+			self.raw.position = self.position;
+			self.raw.read(&mut buf[..len])?
+		} else {
+			if self.decoder.is_none() {
+				let raw = RawEntry { position: 0, file: self.raw.file.clone(), ..self.raw };
+				let mut transforms: Vec<&dyn Transform> = vec![&*codec];
+				transforms.extend(self.transform.as_deref());
+				self.decoder = Some(transform::stack_decoders(&transforms, Box::new(raw)));
+			}
+			match self.decoder {
+				Some(ref mut decoder) => decoder.read(&mut buf[..len])?,
+				None => 0
+			}
+		};
+		self.position += read as u64;
+
+		if let Some(checksum) = self.checksum {
+			let checksum = crc32c::crc32c_append(checksum, &buf[..read]);
+			self.checksum = Some(checksum);
+			if self.position == self.entry.size && self.entry.checksum.is_some_and(|expected| expected != checksum) {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, MpkError::ChecksumMismatch(self.entry.path.clone()).to_string()));
+			}
+		}
+		Ok(read)
+	}
+}
+
+impl<'a, R: Read + Seek + 'a> Seek for EntryReader<'a, R> {
+	/// Seeking in stored entries is instant, but encoded entries have to be decoded up to the new position,
+	/// starting over from the beginning of the entry when seeking backwards
+	fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+		let position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(offset) => offset_position(self.entry.size, offset),
+			SeekFrom::Current(offset) => offset_position(self.position, offset)
+		};
+		let position = match position {
+			None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeked to before the start of the entry")),
+			Some(position) => position
+		};
+
+		if self.is_encoded() {
+			if position < self.position {
+				self.decoder = None;
+				self.position = 0;
+				self.checksum = Some(0);
+			}
+			let skip = position - self.position;
+			std::io::copy(&mut (&mut *self).take(skip), &mut std::io::sink())?;
+		} else if position != self.position {
+			// Skipped data can't be checked, unless we're back at the start
+			self.checksum = if position == 0 { Some(0) } else { None };
+		}
+
+		self.position = position;
+		Ok(position)
+	}
+}
+
+/// Reads the bytes of an entry exactly as they're stored in the archive
+struct RawEntry<'a, R: 'a> {
+	file: Rc<RefCell<&'a mut R>>,
+	start: u64, // Where the entry's data begins in `file`
+	len: u64, // How many bytes of data the entry has
+	position: u64 // How far into the entry's data we are
+}
+
+impl<'a, R: Read + Seek> Read for RawEntry<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let remaining = self.len.saturating_sub(self.position);
+		let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+		if len == 0 {
+			return Ok(0);
+		}
+
+		// Other readers may have moved the file since our last read, so always seek first
+		let mut file = self.file.borrow_mut();
+		file.seek(SeekFrom::Start(self.start + self.position))?;
+		let read = file.read(&mut buf[..len])?;
+		self.position += read as u64;
+		Ok(read)
+	}
+}
+
+/// Moves `position` by `offset`, returning [`None`] if it would go below 0 or overflow
+fn offset_position(position: u64, offset: i64) -> Option<u64> {
+	if offset < 0 {
+		position.checked_sub(offset.unsigned_abs())
+	} else {
+		position.checked_add(offset as u64)
+	}
 }
 
+#[derive(Clone)]
 pub struct FileEntry {
 	pub path: PathBuf,
-	pub size: u64
+	pub size: u64, // The size of the file once extracted
+	pub offset: u64, // Where the file's data starts, counted from the start of the archive
+	pub stored_size: u64, // How many bytes the file's data takes up in the archive
+	pub codec: u8, // The id of the codec the file's data was encoded with
+	pub checksum: Option<u32>, // CRC32C of the file's data once extracted, archives before version 4 don't have them
+	pub metadata: Option<Metadata>, // Permissions, times and ownership, if they were captured when packing
+	pub kind: EntryKind,
+	pub changed: bool // The file changed size or was modified while it was being packed
+}
+
+/// What an entry is, and what it points to if it's a link.
+/// A hard link shares the data of the entry it links to, so it can be read like any other file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+	File,
+	Symlink(PathBuf), // The link's target, exactly as it was read from the filesystem
+	Hardlink(PathBuf), // The path of an earlier entry in the archive
+	Directory
 }
 
 pub struct Header {
 	version: u8, // Version of the archive
-	pub entries: Vec<FileEntry>, // Paths for 
+	pub entries: Vec<FileEntry>, // Paths for
 	pub tags: HashMap<String, String>, // Additional data tags
-	size: u64 // The size of the header in bytes
+	size: u64, // The size of the header in bytes
+	trailing: bool // Whether this is an index at the end of the archive, rather than a header at its start
 }
 
-/// A function type for a function that takes a buffer of data, performs a reversible modification to it, and returns the resulting data.
-type ByteOp = fn(Vec<u8>) -> Vec<u8>;
+impl Header {
+	/// The format version the archive was packed with
+	pub fn version(&self) -> u8 {
+		self.version
+	}
 
-/// A [ByteOp] that does nothing, used as a placeholder for functions that require a ByteOp to be passed
-pub fn nothing(data: Vec<u8>) -> Vec<u8> { data }
+	/// Whether the header was read from an index at the end of the archive, see [`ArchiveBuilder::set_trailing_index`]
+	pub fn is_trailing(&self) -> bool {
+		self.trailing
+	}
+}
 
-/// Takes a `&`[`Path`] to the top level of a path tree, and returns [`Vec`]<[`PathBuf`]> to each file in that path tree
-fn expand_path(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+/// What to leave out while walking a path tree for [`expand_path`]
+struct WalkOptions<'a> {
+	dereference: bool, // Whether symlinks inside the tree are followed
+	filter: &'a PathFilter, // Matched against paths relative to the root of the tree, as they'll be in the archive
+	ignore_files: &'a [String] // Names of .gitignore style files to read in each directory
+}
+
+/// Takes a `&`[`Path`] to the top level of a path tree, and returns [`Vec`]<[`PathBuf`]> to each file, directory and symlink
+/// in that path tree. Directories come before anything inside them, and `path` itself isn't included if it's a directory.
+/// `path` itself is always followed if it's a symlink, but symlinks inside the tree are only followed if `dereference` is set.
+/// Paths that `options` filters out are skipped, along with everything inside them. Broken links and links that loop
+/// are skipped too, and added to `skipped`
+fn expand_path(path: &Path, options: &WalkOptions, skipped: &mut Vec<MpkError>) -> std::io::Result<Vec<PathBuf>> {
 	let mut output_paths = Vec::new();
-	if path.is_dir() {
+	walk_path(path, path, options, &mut Vec::new(), &mut Vec::new(), &mut output_paths, skipped)?;
+	Ok(output_paths)
+}
+
+/// Adds the path tree at `path` to `output_paths`. `ancestors` holds the real paths of the directories
+/// we're currently inside, so a symlink leading back into one of them can't make us go around forever,
+/// and `ignores` holds the ignore rules read from them. Paths that are skipped rather than failing the walk go in `skipped`
+fn walk_path(root: &Path, path: &Path, options: &WalkOptions, ancestors: &mut Vec<PathBuf>, ignores: &mut Vec<IgnoreRules>, output_paths: &mut Vec<PathBuf>, skipped: &mut Vec<MpkError>) -> std::io::Result<()> {
+	let metadata = if options.dereference || ancestors.is_empty() {
+		std::fs::metadata(path)?
+	} else {
+		std::fs::symlink_metadata(path)?
+	};
+
+	// Filter on the path the entry will have in the archive
+	let relative_path = if path == root {
+		if metadata.is_dir() { PathBuf::new() } else { path.file_name().map(PathBuf::from).unwrap_or_default() }
+	} else {
+		path.strip_prefix(root).map(Path::to_path_buf).unwrap_or_default()
+	};
+	let is_root_dir = metadata.is_dir() && path == root;
+	if !is_root_dir && (options.filter.excludes(&relative_path, metadata.is_dir()) || pattern::is_ignored(ignores, &relative_path, metadata.is_dir())) {
+		return Ok(());
+	}
+
+	if metadata.is_dir() {
+		let real_path = path.canonicalize()?;
+		if ancestors.contains(&real_path) {
+			skipped.push(MpkError::LinkLoop(path.to_path_buf()));
+			return Ok(());
+		}
+
+		let before = output_paths.len();
+		if !ancestors.is_empty() {
+			output_paths.push(path.to_path_buf());
+		}
+
+		let ignores_before = ignores.len();
+		for name in options.ignore_files {
+			match std::fs::read_to_string(path.join(name)) {
+				Ok(contents) => ignores.push(IgnoreRules::parse(&relative_path, &contents)),
+				Err(ref why) if why.kind() == std::io::ErrorKind::NotFound => (),
+				Err(why) => return Err(why)
+			}
+		}
+
 		// For each item in the directory, walk its path tree and add the result to our own
+		ancestors.push(real_path);
 		for entry in std::fs::read_dir(path)? {
 			let entry = entry?;
-			output_paths.extend(expand_path(&entry.path())?);
+			if let Err(why) = walk_path(root, &entry.path(), options, ancestors, ignores, output_paths, skipped) {
+				// A broken link is only worth skipping, not giving up on the whole tree for
+				if !options.dereference || why.kind() != std::io::ErrorKind::NotFound {
+					return Err(why);
+				}
+				skipped.push(MpkError::BrokenLink(entry.path()));
+			}
 		}
-	} else if path.is_file() {
+		ancestors.pop();
+		ignores.truncate(ignores_before);
+
+		// With include patterns, a directory is only kept if it matches one or something inside it was kept
+		if !is_root_dir && output_paths.len() == before + 1 && !options.filter.includes(&relative_path, true) {
+			output_paths.pop();
+		}
+	} else if (metadata.is_file() || metadata.file_type().is_symlink()) && options.filter.includes(&relative_path, false) {
 		output_paths.push(path.to_path_buf());
 	}
 
-	Ok(output_paths)
+	Ok(())
 }
 
 // Takes a header structure and returns the bytes that should be written
 // at the front of the archive.
-// Additionally, returns a vec of paths that failed
-// to be processed, these files should not be added to the archive
-fn gen_header(header: &Header, root_paths: &Vec<PathBuf>) -> (Vec<u8>, Vec<PathBuf>) {
-	let mut failed: Vec<PathBuf> = Vec::new();
+// Entry paths should already be relative to the root of the archive
+fn gen_header(header: &Header) -> Result<Vec<u8>, MpkError> {
 	let mut data: Vec<u8> = Vec::new();
 
-	data.write(&[header.version]).expect(&format!("Failed to do a write operation (Line {}", line!())); // Put the archive version at the front
-	// Note: Vec::write apparently can't return an Err(), it just has to say it does because of the rtrait
-	// Because of this, we don't really need to check for Err() and can just expect
-	
-	data.write(&0u64.to_le_bytes()).expect(&format!("Failed to do a write operation (Line {}", line!())); // Reserve a spot for the archive size, which we'll write after
-	
+	// Note: Writing to a Vec can't fail, it just has to say it can because of the trait,
+	// so we use extend_from_slice and skip the error handling entirely
+	if header.version >= FIRST_MAGIC_VERSION {
+		data.extend_from_slice(&MAGIC);
+	}
+	data.push(header.version); // Put the archive version at the front
+	let size_index = data.len();
+	data.extend_from_slice(&0u64.to_le_bytes()); // Reserve a spot for the archive size, which we'll write after
+
 	// Write all the tags
-	// If a tag causes an error, panic and stop. We do this because the tags might hold
-	// information neccesary for taking apart the archive, like compression type.
-	// Also, an invalid tag is more likely the fault of the archiving software than a
-	// user's input file
-	data.write(&(header.tags.len() as u64).to_le_bytes()).expect(&format!("Failed to do a write operation (Line {}", line!())); // Write the number of tags
+	if header.tags.len() as u64 > MAX_TAGS {
+		return Err(MpkError::TooManyTags(header.tags.len() as u64));
+	}
+	data.extend_from_slice(&(header.tags.len() as u64).to_le_bytes()); // Write the number of tags
 	for tag in &header.tags {
-		data.write(&sized_bit_string(tag.0)).expect(&format!("Failed to write tag \"{}\", invalid name", &tag.0));
-		data.write(&sized_bit_string(tag.1)).expect(&format!("Failed to write tag \"{}\", contents invalid", &tag.0));
+		data.extend(sized_bit_string(tag.0));
+		data.extend(sized_bit_string(tag.1));
 	}
 
 	// Write the amount of file entries, as u64
-	data.write(&(header.entries.len() as u64).to_le_bytes()).expect(&format!("Failed to do a write operation (Line {}", line!()));
+	if header.entries.len() as u64 > MAX_ENTRIES {
+		return Err(MpkError::TooManyEntries(header.entries.len() as u64));
+	}
+	data.extend_from_slice(&(header.entries.len() as u64).to_le_bytes());
 	for entry in &header.entries {
-		// Write the file's size
-		data.write(&entry.size.to_le_bytes()).expect(&format!("Failed to do a write operation (Line {}", line!()));
-		
-		// Now that we have the size, we can make the path relative for the archive
-		let mut relative_path = entry.path.to_path_buf();
-		for root in root_paths {
-			if entry.path.starts_with(root) && entry.path != *root {
-				relative_path = entry.path.strip_prefix(root).expect("Unable to make path relative").to_path_buf();
-			}
+		check_entry_path(entry)?; // Don't write archives that we'd refuse to extract
+		let path = entry.path.to_str().ok_or_else(|| MpkError::InvalidUtf8Path(entry.path.clone()))?;
+		if path.len() > MAX_PATH_LENGTH {
+			return Err(MpkError::PathTooLong(path.len() as u64));
 		}
-		
-		// Write the relative path to the file
-		match relative_path.to_str() {
-			None => {
-				println!("Couldn't convert path \"{}\" to a string, maybe it isn't UTF-8? Skipping file", entry.path.display());
-				failed.push(entry.path.clone());
-				continue;
+
+		data.extend_from_slice(&entry.size.to_le_bytes()); // Write the file's size
+		data.extend_from_slice(&entry.offset.to_le_bytes());
+		data.extend_from_slice(&entry.stored_size.to_le_bytes());
+		data.push(entry.codec);
+		let mut flags = 0;
+		if entry.checksum.is_some() { flags |= ENTRY_HAS_CHECKSUM; }
+		if entry.metadata.is_some() { flags |= ENTRY_HAS_METADATA; }
+		if entry.changed { flags |= ENTRY_CHANGED; }
+		data.push(flags);
+		data.extend_from_slice(&entry.checksum.unwrap_or(0).to_le_bytes());
+		if let Some(ref metadata) = entry.metadata {
+			data.extend_from_slice(&metadata.mode.to_le_bytes());
+			for time in &[metadata.modified, metadata.accessed] {
+				data.extend_from_slice(&time.secs.to_le_bytes());
+				data.extend_from_slice(&time.nanos.to_le_bytes());
 			}
-			Some(s) => {
-				data.write(&sized_bit_string(&String::from(s))).expect(&format!("Failed to do a write operation (Line {}", line!()));
+			data.extend_from_slice(&metadata.uid.to_le_bytes());
+			data.extend_from_slice(&metadata.gid.to_le_bytes());
+			// Names that couldn't be looked up are stored empty
+			data.extend(sized_bit_string(metadata.user.as_deref().unwrap_or("")));
+			data.extend(sized_bit_string(metadata.group.as_deref().unwrap_or("")));
+		}
+		match entry.kind {
+			EntryKind::File => data.push(KIND_FILE),
+			EntryKind::Directory => data.push(KIND_DIRECTORY),
+			EntryKind::Symlink(ref target) | EntryKind::Hardlink(ref target) => {
+				data.push(if matches!(entry.kind, EntryKind::Symlink(_)) { KIND_SYMLINK } else { KIND_HARDLINK });
+				let target = target.to_str().ok_or_else(|| MpkError::InvalidUtf8Path(target.clone()))?;
+				if target.len() > MAX_PATH_LENGTH {
+					return Err(MpkError::PathTooLong(target.len() as u64));
+				}
+				data.extend(sized_bit_string(target));
 			}
 		}
+		data.extend(sized_bit_string(path)); // Write the path to the file
+	}
+
+	// Splice in the size of the archive, after the version. The size includes the header checksum at the very end
+	let size = (data.len() + size_of::<u32>()) as u64;
+	if size > MAX_HEADER_SIZE {
+		return Err(MpkError::HeaderTooLarge(size));
 	}
+	data[size_index..size_index + size_of::<u64>()].copy_from_slice(&size.to_le_bytes());
 
-	// Splice in the size of the archive, after the version
-	data.splice(
-		size_of::<u8>()..size_of::<u8>() + size_of::<u64>(), // From size_of(u8) to size_of(u8) + size_of(u64)
-		(data.len() as u64).to_le_bytes().iter().cloned()
-	);
-	return (data, failed);
+	let checksum = crc32c::crc32c(&data);
+	data.extend_from_slice(&checksum.to_le_bytes());
+	Ok(data)
 }
 
-/// Reads a `&mut`[`File`] and returns the archive header if one is found.
-pub fn read_header(file: &mut File) -> Header {
-	let mut index: usize = 0;
-	let mut header = Header {version: 0, entries: Vec::new(), tags: HashMap::new(), size: 0};
+/// What a file looks like from its first few bytes, see [`detect_format`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+	/// An archive from before the magic number (versions 1 to 7), holds its version.
+	/// These start straight with their version byte, so this is only a good guess
+	Legacy(u8),
+	/// An archive starting with [`MAGIC`], holds its version
+	Mpk(u8),
+	/// Anything else
+	NotAnArchive
+}
 
-	// Read in the file signiture, archive version and the header size
-	let mut info_buf: [u8; size_of::<u8>() + size_of::<u64>()] = Default::default();
-	file.read_exact(&mut info_buf).expect("Unable to read archive info");
+/// Works out whether the data at the current position of `file` is an archive, and which kind, without
+/// parsing the header. `file` is left where it started
+///
+/// # Examples
+///
+/// ```ignore
+/// match detect_format(&mut file)? {
+///     Format::NotAnArchive => println!("Not an archive"),
+///     Format::Legacy(version) | Format::Mpk(version) => println!("Archive version {}", version)
+/// }
+/// ```
+pub fn detect_format<R: Read + Seek>(file: &mut R) -> Result<Format, MpkError> {
+	let start = file.stream_position()?;
+	let mut info = Vec::new();
+	file.take((MAGIC.len() + size_of::<u8>() + size_of::<u64>()) as u64).read_to_end(&mut info)?;
+	file.seek(SeekFrom::Start(start))?;
+
+	if info.len() > MAGIC.len() && info[..MAGIC.len()] == MAGIC {
+		return Ok(Format::Mpk(info[MAGIC.len()]));
+	}
 
-	header.version = match info_buf.get(0) {
-		None => panic!("Unable to read archive info"),
-		Some(v) => *v
+	// Without a magic number, the best we can do is check the version and header size make sense.
+	// The smallest header is a version 1 header with no tags or entries
+	const MIN_LEGACY_HEADER_SIZE: u64 = (size_of::<u8>() + size_of::<u64>() * 3) as u64;
+	match (info.first(), read_u64(&info, &mut 1)) {
+		(Some(&version), Ok(size)) if (1..FIRST_MAGIC_VERSION).contains(&version)
+			&& (MIN_LEGACY_HEADER_SIZE..=MAX_HEADER_SIZE).contains(&size) => Ok(Format::Legacy(version)),
+		_ => Ok(Format::NotAnArchive)
+	}
+}
+
+/// Reads an archive header from the current position of `file`, and returns it if one is found.
+pub fn read_header<R: Read>(file: &mut R) -> Result<Header, MpkError> {
+	let mut header = Header {version: 0, entries: Vec::new(), tags: HashMap::new(), size: 0, trailing: false};
+
+	// Read in the archive version and the header size, after the magic number if there is one.
+	// Older archives are at least as long as the magic number, so it's always safe to read that much first
+	let mut info_buf = [0u8; MAGIC.len() + size_of::<u8>() + size_of::<u64>()];
+	read_header_bytes(file, &mut info_buf[..MAGIC.len()])?;
+	let info_len = if info_buf[..MAGIC.len()] == MAGIC {
+		read_header_bytes(file, &mut info_buf[MAGIC.len()..])?;
+		info_buf.len()
+	} else {
+		read_header_bytes(file, &mut info_buf[MAGIC.len()..size_of::<u8>() + size_of::<u64>()])?;
+		size_of::<u8>() + size_of::<u64>()
 	};
+	let info_buf = &info_buf[..info_len];
+	let mut index = info_len - size_of::<u8>() - size_of::<u64>();
 
-	let mut arr: [u8; size_of::<u64>()] = Default::default();
-	arr.copy_from_slice(&info_buf[1..1 + size_of::<u64>()]);
-	header.size = u64::from_le_bytes(arr);
+	header.version = read_u8(info_buf, &mut index)?;
+	let has_magic = info_len == MAGIC.len() + size_of::<u8>() + size_of::<u64>();
+	if !has_magic && !(1..FIRST_MAGIC_VERSION).contains(&header.version) {
+		return Err(MpkError::NotAnArchive);
+	}
+	if !SUPPORTED_ARCHIVE_VERSIONS.contains(&header.version) || (has_magic && header.version < FIRST_MAGIC_VERSION) {
+		return Err(MpkError::UnsupportedVersion(header.version));
+	};
 
-	// TODO: Make this part not awful (the unwrap)
-	let mut data = vec![0u8; header.size.try_into().unwrap()];
-	file.read(&mut data).expect("Unable to read archive header");
+	header.size = read_u64(info_buf, &mut index)?;
+	if has_magic && header.size == 0 && header.version >= FIRST_TRAILING_VERSION {
+		return Err(MpkError::TrailingIndex);
+	}
+	if header.size > MAX_HEADER_SIZE {
+		return Err(MpkError::HeaderTooLarge(header.size));
+	}
 
-	if !SUPPORTED_ARCHIVE_VERSIONS.contains(&header.version) {
-		panic!("This version of the archiver ({}) does not support this archive's version ({}).\nTry updating to the latest version, your current version is {}", ARCHIVE_VERSION, header.version, VERSION);
-	};
+	// The header size includes the info we just read, so only read what's left of it.
+	// The buffer grows as data actually arrives, rather than trusting the size up front
+	let remaining = header.size.checked_sub(info_buf.len() as u64).ok_or(MpkError::TruncatedHeader)?;
+	let mut data = Vec::new();
+	file.take(remaining).read_to_end(&mut data)?;
+	if (data.len() as u64) < remaining {
+		return Err(MpkError::TruncatedHeader);
+	}
 
-// Header version 1
-if header.version == 1 {
+	// Since version 4 the last bytes of the header are a checksum of everything before them
+	if header.version >= 4 {
+		let checksum_start = data.len().checked_sub(size_of::<u32>()).ok_or(MpkError::TruncatedHeader)?;
+		let checksum = crc32c::crc32c_append(crc32c::crc32c(info_buf), &data[..checksum_start]);
+		if data[checksum_start..] != checksum.to_le_bytes() {
+			return Err(MpkError::HeaderChecksumMismatch);
+		}
+		data.truncate(checksum_start);
+	}
 
 	// Tags ******
-	let mut arr: [u8; 8] = Default::default();
-	arr.copy_from_slice(&data[index..index + size_of::<u64>()]);
-	let tag_num = u64::from_le_bytes(arr);
-	index += size_of::<u64>();
+	let mut index: usize = 0;
+	let tag_num = read_u64(&data, &mut index)?;
+	if tag_num > MAX_TAGS {
+		return Err(MpkError::TooManyTags(tag_num));
+	}
 
 	for _ in 0..tag_num {
-		header.tags.insert(read_sized_bit_string(&data, &mut index), read_sized_bit_string(&data, &mut index));
+		let name = String::from_utf8(read_sized_bytes(&data, &mut index)?)
+			.map_err(|why| MpkError::MalformedTag(String::from_utf8_lossy(why.as_bytes()).into_owned()))?;
+		let contents = String::from_utf8(read_sized_bytes(&data, &mut index)?)
+			.map_err(|_| MpkError::MalformedTag(name.clone()))?;
+		header.tags.insert(name, contents);
 	};
 
 	// Files ******
-	let mut arr: [u8; 8] = Default::default();
-	arr.copy_from_slice(&data[index..index + size_of::<u64>()]);
-	let file_num = u64::from_le_bytes(arr);
-	index += size_of::<u64>();
+	let file_num = read_u64(&data, &mut index)?;
+	if file_num > MAX_ENTRIES {
+		return Err(MpkError::TooManyEntries(file_num));
+	}
+	let mut data_offset = header.size; // Version 1 doesn't store offsets, its data is packed right after the header
 
 	for _ in 0..file_num {
-		let mut arr: [u8; 8] = Default::default();
-		arr.copy_from_slice(&data[index..index + size_of::<u64>()]);
-		let file_size = u64::from_le_bytes(arr);
-		index += size_of::<u64>();
+		let file_size = read_u64(&data, &mut index)?;
+		let (offset, stored_size) = if header.version == 1 {
+			(data_offset, file_size)
+		} else {
+			(read_u64(&data, &mut index)?, read_u64(&data, &mut index)?)
+		};
+		// Versions before 3 could only store data as is
+		let codec = if header.version >= 3 {
+			read_u8(&data, &mut index)?
+		} else {
+			Store.id()
+		};
+		let mut flags = 0;
+		let mut checksum = None;
+		if header.version >= 4 {
+			flags = read_u8(&data, &mut index)?;
+			checksum = Some(read_u32(&data, &mut index)?).filter(|_| flags & ENTRY_HAS_CHECKSUM != 0);
+		}
+		let metadata = if header.version >= 5 && flags & ENTRY_HAS_METADATA != 0 {
+			Some(read_metadata(&data, &mut index)?)
+		} else {
+			None
+		};
+		let kind = if header.version >= 6 {
+			let kind = read_u8(&data, &mut index)?;
+			match kind {
+				KIND_FILE => EntryKind::File,
+				KIND_DIRECTORY if header.version >= 7 => EntryKind::Directory,
+				KIND_SYMLINK | KIND_HARDLINK => {
+					let target = read_path(&data, &mut index)?;
+					if kind == KIND_SYMLINK { EntryKind::Symlink(target) } else { EntryKind::Hardlink(target) }
+				},
+				_ => return Err(MpkError::UnknownEntryKind(kind))
+			}
+		} else {
+			EntryKind::File
+		};
+		let path = read_path(&data, &mut index)?;
 
-		header.entries.push(FileEntry { path: PathBuf::from(read_sized_bit_string(&data, &mut index)), size: file_size });
+		data_offset = data_offset.checked_add(stored_size).ok_or(MpkError::TruncatedHeader)?;
+		header.entries.push(FileEntry { path, size: file_size, offset, stored_size, codec, checksum, metadata, kind, changed: flags & ENTRY_CHANGED != 0 });
 	};
 
+	Ok(header)
+}
+
+/// Reads the header of the archive at the current position of `file`, wherever it is. For an archive with a trailing
+/// index that means finding the index through the footer at the end of `file`.
+/// Either way `file` is left where the archive's data could start, just after its header or prefix
+pub fn read_index<R: Read + Seek>(file: &mut R) -> Result<Header, MpkError> {
+	let start = file.stream_position()?;
+	match read_header(file) {
+		Err(MpkError::TrailingIndex) => (),
+		result => return result
+	}
+
+	// Without a footer pointing back to this archive the index was never written, or has been cut off
+	let footer = match read_footer(file)? {
+		Some(footer) if footer.start == start => footer,
+		_ => return Err(MpkError::TruncatedHeader)
+	};
+	file.seek(SeekFrom::Start(start + footer.index_offset))?;
+	let mut header = read_header(file)?;
+	if header.size != footer.index_size || footer.index_offset < TRAILING_PREFIX_SIZE {
+		return Err(MpkError::TruncatedHeader);
+	}
+	header.trailing = true;
+	file.seek(SeekFrom::Start(start + TRAILING_PREFIX_SIZE))?;
+	Ok(header)
+}
+
+/// Where an archive with a trailing index is in a file, as told by the footer at the end of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+	pub start: u64, // Where the archive begins in the file
+	pub index_offset: u64, // Where the index begins, counted from the start of the archive
+	pub index_size: u64
+}
+
+/// Reads the footer at the end of `file`, if it ends with one. This finds archives with a trailing index even when
+/// they've been glued onto the end of other data, like a program that extracts them. `file` is left where it started
+pub fn read_footer<R: Read + Seek>(file: &mut R) -> Result<Option<Footer>, MpkError> {
+	let position = file.stream_position()?;
+	let len = file.seek(SeekFrom::End(0))?;
+	let mut footer = None;
+	if len >= TRAILING_PREFIX_SIZE + FOOTER_SIZE {
+		let mut data = [0u8; FOOTER_SIZE as usize];
+		file.seek(SeekFrom::Start(len - FOOTER_SIZE))?;
+		file.read_exact(&mut data)?;
+
+		let mut index = 0;
+		let index_offset = read_u64(&data, &mut index).unwrap_or(0);
+		let index_size = read_u64(&data, &mut index).unwrap_or(0);
+		let start = (len - FOOTER_SIZE).checked_sub(index_size).and_then(|end| end.checked_sub(index_offset));
+		match start {
+			Some(start) if data[index..] == FOOTER_MAGIC => footer = Some(Footer { start, index_offset, index_size }),
+			_ => ()
+		}
+	}
+	file.seek(SeekFrom::Start(position))?;
+	Ok(footer)
+}
+
+/// The start of an archive with a trailing index, which tells readers to look for the footer
+fn trailing_prefix() -> Vec<u8> {
+	let mut data = MAGIC.to_vec();
+	data.push(ARCHIVE_VERSION);
+	data.extend_from_slice(&0u64.to_le_bytes());
+	data
+}
+
+/// Writes `header` as an index `index_offset` bytes from the start of the archive, followed by the footer that points to it.
+/// Returns how many bytes were written
+fn write_index<W: Write>(file: &mut W, index_offset: u64, header: &Header) -> Result<u64, MpkError> {
+	let index = gen_header(header)?;
+	file.write_all(&index)?;
+	file.write_all(&index_offset.to_le_bytes())?;
+	file.write_all(&(index.len() as u64).to_le_bytes())?;
+	file.write_all(&FOOTER_MAGIC)?;
+	Ok(index.len() as u64 + FOOTER_SIZE)
 }
 
-	header
+/// Reads an entry path or link target from the header, starting from (index), and adds its size to (index)
+fn read_path(data: &[u8], index: &mut usize) -> Result<PathBuf, MpkError> {
+	// Peek at the length first, so a long path is reported as such rather than as whatever it runs into
+	let len = read_u64(data, &mut index.clone())?;
+	if len > MAX_PATH_LENGTH as u64 {
+		return Err(MpkError::PathTooLong(len));
+	}
+	let path = String::from_utf8(read_sized_bytes(data, index)?)
+		.map_err(|why| MpkError::InvalidUtf8Path(PathBuf::from(String::from_utf8_lossy(why.as_bytes()).into_owned())))?;
+	Ok(PathBuf::from(path))
+}
+
+/// Reads the metadata of an entry from the header, starting from (index)
+fn read_metadata(data: &[u8], index: &mut usize) -> Result<Metadata, MpkError> {
+	let mode = read_u32(data, index)?;
+	let mut times = [Timestamp::default(); 2];
+	for time in &mut times {
+		time.secs = read_u64(data, index)? as i64;
+		time.nanos = read_u32(data, index)?;
+	}
+	let uid = read_u32(data, index)?;
+	let gid = read_u32(data, index)?;
+	let mut names = Vec::new();
+	for _ in 0..2 {
+		// Names aren't needed to extract anything, so one that's gibberish is just dropped
+		let name = String::from_utf8(read_sized_bytes(data, index)?).ok().filter(|name| !name.is_empty());
+		names.push(name);
+	}
+	let group = names.pop().unwrap_or_default();
+	let user = names.pop().unwrap_or_default();
+
+	Ok(Metadata { mode, modified: times[0], accessed: times[1], uid, gid, user, group })
+}
+
+/// Fills `buffer` from `file`, treating running out of bytes as a truncated header
+fn read_header_bytes<R: Read>(file: &mut R, buffer: &mut [u8]) -> Result<(), MpkError> {
+	file.read_exact(buffer).map_err(|why| match why.kind() {
+		std::io::ErrorKind::UnexpectedEof => MpkError::TruncatedHeader,
+		_ => MpkError::Io(why)
+	})
 }
 
 // Pack functions ********************************************************
@@ -182,201 +986,1067 @@ if header.version == 1 {
 /// Creates an archive on `archive_file`, containing all paths contained by `root_paths`,
 /// paths specified in `root_paths` will be located at the root of the archive, while folders
 /// will recursively include paths they contain.
-/// Tags can be added with `tags`, which can be used for arbitrary metadata
-pub fn pack_archive(archive_file: &mut File, root_paths: &Vec<PathBuf>, tags: HashMap<String, String>) {
-	let mut header = Header {
-		version: ARCHIVE_VERSION,
-		tags: tags,
-		size: 0,
-		entries: get_file_sizes(expand_paths(&root_paths))
+/// Tags can be added with `tags`, which can be used for arbitrary metadata.
+/// Paths that couldn't be packed are left out, and returned along with any other warnings
+pub fn pack_archive<W: Write + Seek>(archive_file: &mut W, root_paths: &[PathBuf], tags: HashMap<String, String>) -> Result<Vec<MpkError>, MpkError> {
+	let mut warnings = Vec::new();
+	let mut bad_roots = Vec::new();
+	let mut builder = ArchiveBuilder::new();
+	builder.set_warning_handler(|warning| warnings.push(warning));
+	for root in root_paths {
+		if let Err(why) = builder.add_path(root) {
+			bad_roots.push(match why {
+				MpkError::Io(why) => MpkError::UnreadablePath(root.clone(), why),
+				why => why
+			});
+		}
+	}
+	for (name, value) in tags {
+		builder.set_tag(&name, &value);
+	}
+
+	builder.finish(archive_file)?;
+	warnings.extend(bad_roots);
+	Ok(warnings)
+}
+
+/// The time to record an archive as created at. This is now, unless `SOURCE_DATE_EPOCH` is set to make reproducible archives
+fn creation_time() -> Timestamp {
+	match env::var("SOURCE_DATE_EPOCH").ok().and_then(|secs| secs.trim().parse().ok()) {
+		Some(secs) => Timestamp { secs, nanos: 0 },
+		None => Timestamp::from(std::time::SystemTime::now())
+	}
+}
+
+/// Where the data for an entry added to an [`ArchiveBuilder`] comes from
+enum EntrySource<'a> {
+	File(PathBuf, Option<std::time::SystemTime>), // Along with when it was modified as it was added, to tell if it changes before it's packed
+	Bytes(Vec<u8>),
+	Reader(Box<dyn Read + 'a>),
+	Stream(Box<dyn Read + 'a>), // Read until it ends, its size is only known once it has
+	NoData // Links and directories have no data of their own
+}
+
+/// Composes an archive one entry at a time. Nothing is written until [`ArchiveBuilder::finish`] is called
+///
+/// # Examples
+///
+/// ```ignore
+/// let mut builder = ArchiveBuilder::new();
+/// builder.add_path(Path::new("assets"))?;
+/// builder.add_bytes(Path::new("generated/version.txt"), b"1.0.0".to_vec());
+/// builder.set_tag("game", "Example");
+/// builder.finish(&mut File::create("assets.mpk")?)?;
+/// ```
+pub struct ArchiveBuilder<'a> {
+	entries: Vec<PendingEntry<'a>>,
+	tags: HashMap<String, String>,
+	codec: Rc<dyn Codec>, // The codec given to entries as they're added
+	transforms: Chain, // Applied to every entry after its codec
+	dereference: bool, // Whether add_path follows symlinks rather than storing them
+	filter: PathFilter, // Paths under add_path's roots that are left out
+	ignore_files: Vec<String>, // Names of .gitignore style files that add_path reads, none by default
+	header_reserve: u64, // Free space left after the header, so it can grow without moving any data
+	trailing_index: bool, // Whether the header goes at the end of the archive as an index, after the data
+	on_change: ChangePolicy, // What happens when a file changes between being added and being packed
+	on_warning: Box<dyn FnMut(MpkError) + 'a>, // Told about paths that are skipped, and files that change with ChangePolicy::Warn
+	hardlinks: HashMap<(u64, u64), PathBuf> // The archive path of each file with more than one link, by device and inode
+}
+
+/// An entry that will be written when its [`ArchiveBuilder`] is finished, or appended to an [`Archive`]
+struct PendingEntry<'a> {
+	entry: FileEntry, // The path is where the data will go in the archive
+	source: EntrySource<'a>,
+	codec: Rc<dyn Codec>
+}
+
+impl<'a> Default for ArchiveBuilder<'a> {
+	fn default() -> Self {
+		ArchiveBuilder::new()
+	}
+}
+
+impl<'a> ArchiveBuilder<'a> {
+	/// Creates a builder with no entries. The writer and creation time tags are filled in already,
+	/// though they can be overwritten with [`ArchiveBuilder::set_tag`]
+	pub fn new() -> ArchiveBuilder<'a> {
+		let mut tags = HashMap::new();
+		tags.insert(TAG_WRITER.to_string(), env!("CARGO_PKG_NAME").to_string());
+		tags.insert(TAG_WRITER_VERSION.to_string(), VERSION.to_string());
+		tags.insert(TAG_CREATED.to_string(), creation_time().to_string());
+
+		ArchiveBuilder {
+			entries: Vec::new(),
+			tags,
+			codec: Rc::new(Store),
+			transforms: Chain::new(),
+			dereference: false,
+			filter: PathFilter::new(),
+			ignore_files: Vec::new(),
+			header_reserve: HEADER_RESERVE,
+			trailing_index: false,
+			on_change: ChangePolicy::Warn,
+			on_warning: Box::new(|_| ()),
+			hardlinks: HashMap::new()
+		}
+	}
+
+	/// Adds every file under `path`. The contents of a folder will be located at the root of the archive,
+	/// while a single file will be placed at the root under its own name.
+	/// Symlinks inside `path` are stored as links unless [`ArchiveBuilder::set_dereference`] is set, and files
+	/// that are hard linked to one another are only stored once.
+	/// Paths are left out if they're filtered out by [`ArchiveBuilder::set_filter`] or [`ArchiveBuilder::set_ignore_files`].
+	/// Ones that can't be read are skipped and passed to the handler set with [`ArchiveBuilder::set_warning_handler`]
+	pub fn add_path(&mut self, path: &Path) -> Result<&mut ArchiveBuilder<'a>, MpkError> {
+		let is_dir = std::fs::metadata(path)?.is_dir();
+
+		let mut skipped = Vec::new();
+		let options = WalkOptions { dereference: self.dereference, filter: &self.filter, ignore_files: &self.ignore_files };
+		let paths = expand_path(path, &options, &mut skipped)?;
+		for (mut entry, fs_metadata) in get_file_sizes(paths, self.dereference, &mut skipped) {
+			// Don't add the same file twice when roots overlap
+			if self.entries.iter().any(|pending| matches!(pending.source, EntrySource::File(ref p, _) if *p == entry.path)) {
+				continue;
+			}
+
+			// Make the path relative for the archive
+			let relative_path = if is_dir {
+				entry.path.strip_prefix(path).map(Path::to_path_buf).ok()
+			} else {
+				entry.path.file_name().map(PathBuf::from)
+			};
+			let relative_path = match relative_path {
+				Some(p) if p.to_str().is_some() => p,
+				_ => {
+					skipped.push(MpkError::InvalidUtf8Path(entry.path));
+					continue;
+				}
+			};
+
+			match entry.kind {
+				EntryKind::Symlink(target) => {
+					self.push(&relative_path, 0, entry.metadata, EntrySource::NoData);
+					self.set_kind(EntryKind::Symlink(target));
+					continue;
+				},
+				EntryKind::Directory => {
+					// Overlapping roots can lead to the same directory twice
+					if !self.entries.iter().any(|pending| pending.entry.kind == EntryKind::Directory && pending.entry.path == relative_path) {
+						self.push(&relative_path, 0, entry.metadata, EntrySource::NoData);
+						self.set_kind(EntryKind::Directory);
+					}
+					continue;
+				},
+				_ => {}
+			}
+
+			if let Some(id) = metadata::hardlink_id(&fs_metadata) {
+				if let Some(target) = self.hardlinks.get(&id).cloned() {
+					self.push(&relative_path, entry.size, entry.metadata.take(), EntrySource::NoData);
+					self.set_kind(EntryKind::Hardlink(target));
+					continue;
+				}
+				self.hardlinks.insert(id, relative_path.clone());
+			}
+
+			let modified = fs_metadata.modified().ok();
+			self.push(&relative_path, entry.size, entry.metadata, EntrySource::File(entry.path, modified));
+		}
+
+		for warning in skipped {
+			(self.on_warning)(warning);
+		}
+		Ok(self)
+	}
+
+	/// Adds the file at `src`, placing it at `archive_path` inside the archive
+	pub fn add_file_as(&mut self, src: &Path, archive_path: &Path) -> Result<&mut ArchiveBuilder<'a>, MpkError> {
+		let metadata = std::fs::metadata(src)?;
+		self.push(archive_path, metadata.len(), Some(Metadata::from_fs(&metadata)), EntrySource::File(src.to_path_buf(), metadata.modified().ok()));
+		Ok(self)
+	}
+
+	/// Adds everything `reader` gives until it ends as a file at `archive_path` inside the archive,
+	/// for data whose length isn't known up front like a pipe
+	pub fn add_stream<R: Read + 'a>(&mut self, archive_path: &Path, reader: R) -> &mut ArchiveBuilder<'a> {
+		self.push(archive_path, 0, None, EntrySource::Stream(Box::new(reader)));
+		self
+	}
+
+	/// Adds `data` as a file at `archive_path` inside the archive
+	pub fn add_bytes(&mut self, archive_path: &Path, data: Vec<u8>) -> &mut ArchiveBuilder<'a> {
+		let size = data.len() as u64;
+		self.push(archive_path, size, None, EntrySource::Bytes(data));
+		self
+	}
+
+	/// Adds a file at `archive_path` inside the archive, whose contents are the next `len` bytes of `reader`.
+	/// `reader` isn't read until the archive is finished
+	pub fn add_reader<R: Read + 'a>(&mut self, archive_path: &Path, reader: R, len: u64) -> &mut ArchiveBuilder<'a> {
+		self.push(archive_path, len, None, EntrySource::Reader(Box::new(reader)));
+		self
+	}
+
+	/// Adds a symlink at `archive_path` inside the archive, pointing to `target`
+	pub fn add_symlink(&mut self, archive_path: &Path, target: &Path) -> &mut ArchiveBuilder<'a> {
+		self.push(archive_path, 0, None, EntrySource::NoData);
+		self.set_kind(EntryKind::Symlink(target.to_path_buf()))
+	}
+
+	/// Adds an empty directory at `archive_path` inside the archive. Directories don't need to be added for
+	/// the files inside them to be extracted, but this keeps them when they're empty
+	pub fn add_directory(&mut self, archive_path: &Path) -> &mut ArchiveBuilder<'a> {
+		self.push(archive_path, 0, None, EntrySource::NoData);
+		self.set_kind(EntryKind::Directory)
+	}
+
+	/// Makes [`ArchiveBuilder::add_path`] store the files that symlinks point to, rather than the links themselves.
+	/// Links that lead back into a directory they're in are skipped
+	pub fn set_dereference(&mut self, dereference: bool) -> &mut ArchiveBuilder<'a> {
+		self.dereference = dereference;
+		self
+	}
+
+	/// Leaves out paths under the roots given to [`ArchiveBuilder::add_path`] that `filter` doesn't allow.
+	/// Patterns are matched against the paths the entries would have in the archive, and a directory that's
+	/// excluded isn't looked inside of
+	pub fn set_filter(&mut self, filter: PathFilter) -> &mut ArchiveBuilder<'a> {
+		self.filter = filter;
+		self
+	}
+
+	/// Reads ignore files with these names (such as [`pattern::DEFAULT_IGNORE_FILES`]) in each directory
+	/// [`ArchiveBuilder::add_path`] walks through, and leaves out the paths they ignore
+	pub fn set_ignore_files<S: AsRef<str>>(&mut self, names: &[S]) -> &mut ArchiveBuilder<'a> {
+		self.ignore_files = names.iter().map(|name| name.as_ref().to_string()).collect();
+		self
+	}
+
+	/// Sets the tag `name` to `value`, replacing any value it had before
+	pub fn set_tag(&mut self, name: &str, value: &str) -> &mut ArchiveBuilder<'a> {
+		self.tags.insert(name.to_string(), value.to_string());
+		self
+	}
+
+	/// Records the name of this machine in the archive's tags, if it can be found
+	pub fn record_host(&mut self) -> &mut ArchiveBuilder<'a> {
+		if let Some(host) = metadata::host_name() {
+			self.set_tag(TAG_HOST, &host);
+		}
+		self
+	}
+
+	/// Records the command line that packed the archive in its tags. Arguments containing spaces or quotes are quoted
+	pub fn record_command(&mut self, args: &[String]) -> &mut ArchiveBuilder<'a> {
+		let args: Vec<String> = args.iter().map(|arg| {
+			if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+				format!("{:?}", arg)
+			} else {
+				arg.clone()
+			}
+		}).collect();
+		self.set_tag(TAG_COMMAND, &args.join(" "))
+	}
+
+	/// Encodes the data of entries added after this with `codec`. Entries are stored as is by default
+	pub fn set_codec(&mut self, codec: Box<dyn Codec>) -> &mut ArchiveBuilder<'a> {
+		self.codec = Rc::from(codec);
+		self
+	}
+
+	/// Applies `transform` to the data of every entry after it's been encoded by its codec, for things like encryption.
+	/// Transforms aren't recorded in the archive, so readers need to be given them with [`Archive::set_transform`].
+	/// When more than one is added they're applied in the order they were added
+	pub fn add_transform(&mut self, transform: Box<dyn Transform>) -> &mut ArchiveBuilder<'a> {
+		self.transforms.push(transform);
+		self
+	}
+
+	/// Sets the metadata of the most recently added entry, replacing any that was captured from the filesystem.
+	/// Entries added from bytes or readers have no metadata unless it's given here
+	pub fn set_metadata(&mut self, metadata: Metadata) -> &mut ArchiveBuilder<'a> {
+		if let Some(pending) = self.entries.last_mut() {
+			pending.entry.metadata = Some(metadata);
+		}
+		self
+	}
+
+	fn push(&mut self, archive_path: &Path, size: u64, metadata: Option<Metadata>, source: EntrySource<'a>) {
+		// Links have no data to encode
+		let codec: Rc<dyn Codec> = if matches!(source, EntrySource::NoData) { Rc::new(Store) } else { self.codec.clone() };
+		let entry = FileEntry {
+			path: archive_path.to_path_buf(),
+			size,
+			offset: 0,
+			stored_size: 0,
+			codec: codec.id(),
+			checksum: None,
+			metadata,
+			kind: EntryKind::File,
+			changed: false
+		};
+		self.entries.push(PendingEntry { entry, source, codec });
+	}
+
+	/// Changes the kind of the most recently added entry
+	fn set_kind(&mut self, kind: EntryKind) -> &mut ArchiveBuilder<'a> {
+		if let Some(pending) = self.entries.last_mut() {
+			pending.entry.kind = kind;
+		}
+		self
+	}
+
+	/// Leaves `bytes` of free space after the header, so entries and tags can be added to the archive later
+	/// without moving its data. [`HEADER_RESERVE`] bytes are left by default
+	pub fn set_header_reserve(&mut self, bytes: u64) -> &mut ArchiveBuilder<'a> {
+		self.header_reserve = bytes;
+		self
+	}
+
+	/// Puts the archive's header at its end as an index, after the entries' data, rather than at its start.
+	/// The archive can then be written in a single pass, see [`ArchiveBuilder::finish_streaming`], and glued onto the end
+	/// of other files. Adding to it later doesn't ever move its data either
+	pub fn set_trailing_index(&mut self, trailing: bool) -> &mut ArchiveBuilder<'a> {
+		self.trailing_index = trailing;
+		self
+	}
+
+	/// Decides what happens when a file added from disk has changed by the time its data is packed. Either way
+	/// exactly as many bytes as the file had when it was added are packed, so the rest of the archive is unaffected.
+	/// Files are only warned about by default
+	pub fn set_on_change(&mut self, policy: ChangePolicy) -> &mut ArchiveBuilder<'a> {
+		self.on_change = policy;
+		self
+	}
+
+	/// Calls `handler` with anything worth warning about that doesn't stop the archive being packed, like paths
+	/// [`ArchiveBuilder::add_path`] has to skip or files that change while being packed. They're ignored by default
+	pub fn set_warning_handler<F: FnMut(MpkError) + 'a>(&mut self, handler: F) -> &mut ArchiveBuilder<'a> {
+		self.on_warning = Box::new(handler);
+		self
+	}
+
+	/// Writes the archive to `archive_file`, reading each entry's data as it goes
+	pub fn finish<W: Write + Seek>(mut self, archive_file: &mut W) -> Result<(), MpkError> {
+		if self.trailing_index {
+			return self.finish_streaming(archive_file);
+		}
+
+		let start = archive_file.stream_position()?;
+		let mut header = Header {
+			version: ARCHIVE_VERSION,
+			tags: self.tags,
+			size: 0,
+			entries: self.entries.iter().map(|pending| pending.entry.clone()).collect(),
+			trailing: false
+		};
+
+		// Where each entry's data ends up isn't known until it's been encoded, so write a placeholder
+		// header for now. Every field but the paths and tags has a fixed size, so it won't change size
+		archive_file.write_all(&gen_header(&header)?)?;
+		std::io::copy(&mut std::io::repeat(0).take(self.header_reserve), archive_file)?;
+
+		header.entries = write_entries(self.entries, &self.transforms, self.on_change, &mut *self.on_warning, archive_file, start, &[])?;
+
+		// Now go back and fill in the real header
+		let end = archive_file.stream_position()?;
+		archive_file.seek(SeekFrom::Start(start))?;
+		archive_file.write_all(&gen_header(&header)?)?;
+		archive_file.seek(SeekFrom::Start(end))?;
+
+		Ok(())
+	}
+
+	/// Writes the archive to `archive_file` in a single pass, with a trailing index, so it can be written somewhere
+	/// that can't seek like a pipe
+	pub fn finish_streaming<W: Write>(mut self, archive_file: &mut W) -> Result<(), MpkError> {
+		let mut output = PositionWriter { inner: archive_file, position: 0 };
+		output.write_all(&trailing_prefix())?;
+		let entries = write_entries(self.entries, &self.transforms, self.on_change, &mut *self.on_warning, &mut output, 0, &[])?;
+
+		let header = Header { version: ARCHIVE_VERSION, entries, tags: self.tags, size: 0, trailing: true };
+		let index_offset = output.position;
+		write_index(&mut output, index_offset, &header)?;
+		output.flush()?;
+		Ok(())
+	}
+}
+
+/// Counts the bytes written to `inner`, so writers that can't seek can still say where they are.
+/// Seeking anywhere other than where it already is fails
+struct PositionWriter<W> {
+	inner: W,
+	position: u64
+}
+
+impl<W: Write> Write for PositionWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let len = self.inner.write(buf)?;
+		self.position += len as u64;
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> { self.inner.flush() }
+}
+
+impl<W> Seek for PositionWriter<W> {
+	fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+		match position {
+			SeekFrom::Current(0) => Ok(self.position),
+			SeekFrom::Start(position) if position == self.position => Ok(position),
+			_ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Can't seek while streaming an archive"))
+		}
+	}
+}
+
+/// What happens when a file changes between being added to an [`ArchiveBuilder`] and its data being packed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangePolicy {
+	Warn, // The entry is packed and flagged as changed, and MpkError::FileChanged is given to the builder's warning handler
+	Fail // Packing stops with MpkError::FileChanged
+}
+
+/// Writes the data of each of `entries` to `archive_file` from its current position, and returns the entries with where
+/// their data went filled in. Offsets are counted from `start`, and hard links can link to any of `earlier` as well as
+/// to the files in `entries` before them
+fn write_entries<'a, W: Write + Seek>(entries: Vec<PendingEntry<'a>>, transforms: &Chain, on_change: ChangePolicy, on_warning: &mut dyn FnMut(MpkError), archive_file: &mut W, start: u64, earlier: &[FileEntry]) -> Result<Vec<FileEntry>, MpkError> {
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut written: Vec<FileEntry> = Vec::with_capacity(entries.len());
+	for pending in entries {
+		let mut entry = pending.entry;
+		let data_start = archive_file.stream_position()?;
+		entry.offset = data_start - start;
+
+		let mut stack: Vec<&dyn Transform> = vec![&*pending.codec];
+		if !transforms.is_empty() {
+			stack.push(transforms);
+		}
+		let checksum = match pending.source {
+			EntrySource::File(path, modified) => {
+				// A file that's been removed or can't be opened any more is packed as zeros, like one that shrank to nothing
+				let (checksum, unchanged) = match File::open(&path) {
+					Ok(mut file) => {
+						let (checksum, complete) = append_file(&mut file, entry.size, archive_file, &stack, &mut buffer)?;
+						(checksum, complete && file.metadata()?.modified().ok() == modified)
+					},
+					Err(_) => (append_to_archive(&mut std::io::repeat(0), entry.size, archive_file, &stack, &mut buffer)?, false)
+				};
+				if !unchanged {
+					match on_change {
+						ChangePolicy::Warn => on_warning(MpkError::FileChanged(path)),
+						ChangePolicy::Fail => return Err(MpkError::FileChanged(path))
+					}
+					entry.changed = true;
+				}
+				Some(checksum)
+			},
+			EntrySource::Bytes(data) => Some(append_to_archive(&mut &data[..], entry.size, archive_file, &stack, &mut buffer)?),
+			EntrySource::Reader(mut reader) => Some(append_to_archive(&mut reader, entry.size, archive_file, &stack, &mut buffer)?),
+			EntrySource::Stream(mut reader) => {
+				let (checksum, size) = append_stream(&mut reader, archive_file, &stack, &mut buffer)?;
+				entry.size = size;
+				Some(checksum)
+			},
+			EntrySource::NoData => None
+		};
+		if checksum.is_some() {
+			entry.stored_size = archive_file.stream_position()? - data_start;
+			entry.checksum = checksum;
+		}
+
+		// Hard links share the data of the file they link to
+		if let EntryKind::Hardlink(ref target) = entry.kind {
+			let target = earlier.iter().chain(written.iter()).rev()
+				.find(|earlier| earlier.path == *target && earlier.kind == EntryKind::File)
+				.ok_or_else(|| MpkError::MissingLinkTarget(target.clone()))?;
+			entry.size = target.size;
+			entry.offset = target.offset;
+			entry.stored_size = target.stored_size;
+			entry.codec = target.codec;
+			entry.checksum = target.checksum;
+			entry.changed = target.changed;
+		}
+		written.push(entry);
+	}
+
+	Ok(written)
+}
+
+/// Writes the next `size` bytes of `file` to `archive_file`, passing them through each of `transforms` in order.
+/// The data is copied through `buffer`, so memory use doesn't depend on `size`. Returns the checksum of the data read
+fn append_to_archive<R: Read, W: Write>(file: &mut R, size: u64, archive_file: &mut W, transforms: &[&dyn Transform], buffer: &mut [u8]) -> std::io::Result<u32> {
+	let mut encoder = transform::stack_encoders(transforms, Box::new(PlainEncoder(archive_file)));
+	let mut input = ChecksumReader { inner: file, checksum: 0 };
+	copy_exact(&mut input, &mut encoder, size, buffer)?;
+	encoder.finish()?;
+	Ok(input.checksum)
+}
+
+/// Like [`append_to_archive`], but for a file that might have changed since `size` was taken. Exactly `size` bytes
+/// are written whatever happened to it, padded with zeros if it's got shorter and cut off if it's got longer.
+/// Returns the checksum of what was written, and whether the file was still `size` bytes long
+fn append_file<R: Read, W: Write>(file: &mut R, size: u64, archive_file: &mut W, transforms: &[&dyn Transform], buffer: &mut [u8]) -> std::io::Result<(u32, bool)> {
+	let mut contents = file.by_ref().take(size);
+	let checksum = append_to_archive(&mut (&mut contents).chain(std::io::repeat(0)), size, archive_file, transforms, buffer)?;
+	let shrunk = contents.limit() > 0;
+	let grown = loop {
+		match file.read(&mut [0u8]) {
+			Ok(len) => break len > 0,
+			Err(ref why) if why.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(why) => return Err(why)
+		}
+	};
+	Ok((checksum, !shrunk && !grown))
+}
+
+/// Encodes everything `file` gives until it ends into `archive_file`, returning the checksum of the data and how long it was
+fn append_stream<R: Read, W: Write>(file: &mut R, archive_file: &mut W, transforms: &[&dyn Transform], buffer: &mut [u8]) -> std::io::Result<(u32, u64)> {
+	let mut encoder = transform::stack_encoders(transforms, Box::new(PlainEncoder(archive_file)));
+	let mut input = ChecksumReader { inner: file, checksum: 0 };
+	let mut size = 0;
+	loop {
+		let len = match input.read(buffer) {
+			Ok(0) => break,
+			Ok(len) => len,
+			Err(ref why) if why.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(why) => return Err(why)
+		};
+		encoder.write_all(&buffer[..len])?;
+		size += len as u64;
+	}
+	encoder.finish()?;
+	Ok((input.checksum, size))
+}
+
+// Unpack functions ********************************************************
+
+/// Controls what gets restored when extracting entries, and where they go
+pub struct ExtractOptions {
+	pub restore_ownership: bool, // Give files back to the user and group that owned them, only possible as root
+	pub restore_permissions: bool,
+	pub restore_special_bits: bool, // Keep setuid, setgid and sticky bits when restoring permissions, which untrusted archives shouldn't get
+	pub restore_times: bool,
+	pub strip_components: usize, // Leading components taken off each entry's path, entries with no more than this are skipped
+	pub flatten: bool // Put every file straight into the output directory under its own name, and skip directories
+}
+
+impl Default for ExtractOptions {
+	/// Restores everything, except ownership and setuid, setgid and sticky bits when not running as root,
+	/// and keeps entry paths as they are
+	fn default() -> ExtractOptions {
+		ExtractOptions {
+			restore_ownership: metadata::is_privileged(),
+			restore_permissions: true,
+			restore_special_bits: metadata::is_privileged(),
+			restore_times: true,
+			strip_components: 0,
+			flatten: false
+		}
+	}
+}
+
+impl ExtractOptions {
+	/// Where `entry` should be extracted to, relative to the output directory. `None` if it should be skipped
+	pub fn output_path(&self, entry: &FileEntry) -> Option<PathBuf> {
+		let path: PathBuf = entry.path.components()
+			.filter(|component| matches!(component, Component::Normal(_)))
+			.skip(self.strip_components)
+			.collect();
+		if path.as_os_str().is_empty() {
+			return None;
+		}
+
+		if self.flatten {
+			match entry.kind {
+				EntryKind::Directory => None,
+				_ => path.file_name().map(PathBuf::from)
+			}
+		} else {
+			Some(path)
+		}
+	}
+}
+
+/// Extracts every entry of the archive starting at the current position of `file` into `out_path`
+pub fn unpack_archive<R: Read + Seek>(file: R, out_path: &Path, options: &ExtractOptions) -> Result<(), MpkError> {
+	// Try to create the directory to extract to
+	std::fs::create_dir_all(out_path)?;
+
+	let mut archive = Archive::new(file)?;
+	extract_all_archive(&mut archive, out_path, options)
+}
+
+
+// Finds a file (path_in_archive) in an archive and copies it to (out_path). When more than one entry has the path
+// the last one wins, like it does when extracting them all
+pub fn extract_from_archive<R: Read + Seek, W: Write>(path_in_archive: &Path, archive: &mut Archive<R>, mut out_file: &mut W) -> Result<(), MpkError> {
+	let entry = match archive.header.entries.iter().rev().find(|entry| entry.path == *path_in_archive) {
+		None => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such file in the archive").into()),
+		Some(entry) => entry
 	};
 
-	let h_data = gen_header(&header, &root_paths);
-	let failed_paths = h_data.1;
-	
-	// Remove failed paths
-	for p in &failed_paths {
-		match header.entries.iter().position(|r| r.path == *p) {
-			Some(i) => {
-				header.entries.remove(i);
-			},
-			None => ()
-		};
-	}
+	let transforms = archive.entry_transforms(entry)?;
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	buffered_copy(&mut archive.file, &mut out_file, archive.start + entry.offset, entry, &transforms, &mut buffer)?;
+	Ok(())
+}
+
+/// Extracts every entry of `archive` into `out_path`. Entries can't be written outside of `out_path`,
+/// whether by their path or by following a symlink, see [`sandbox`]
+pub fn extract_all_archive<R: Read + Seek>(archive: &mut Archive<R>, out_path: &Path, options: &ExtractOptions) -> Result<(), MpkError> {
+	let indices: Vec<usize> = (0..archive.header.entries.len()).collect();
+	extract_entries(archive, &indices, out_path, options)
+}
 
-	// Write the header data from gen_header()
-	archive_file.write(&h_data.0).expect("Failed to write to archive");
+/// Extracts the entries at `indices` in the archive's header into `out_path`, at the path [`ExtractOptions::output_path`]
+/// gives each of them. The same rules as [`extract_all_archive`] apply, so nothing can be written outside of `out_path`.
+/// Hard links are recreated if what they link to is extracted too, otherwise they're written out as a copy of it
+pub fn extract_entries<R: Read + Seek>(archive: &mut Archive<R>, indices: &[usize], out_path: &Path, options: &ExtractOptions) -> Result<(), MpkError> {
+	// Check every path before writing anything, so a bad archive doesn't leave half its contents behind
+	for &index in indices {
+		check_entry_path(&archive.header.entries[index])?;
+	}
 
-	// Append the files to the archive_file file
-	for entry in &mut header.entries.iter() {
-		let mut file = match File::open(&entry.path) {
-			Err(why) => { 
-				panic!("Failed to open file \"{}\", stopping. {}", entry.path.display(), why);
-			},
-			Ok(f) => f
+	std::fs::create_dir_all(out_path)?;
+	let sandbox = Sandbox::open(out_path)?;
+
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut extracted: HashMap<PathBuf, PathBuf> = HashMap::new(); // Where each file went, so hard links can find it
+	let mut directories = Vec::new(); // Extracting into a directory changes its mtime, so they're done last
+	for index in data_order(&archive.header.entries, indices) {
+		let entry = &archive.header.entries[index];
+		let out = match options.output_path(entry) {
+			None => continue,
+			Some(out) => out
 		};
 
-		match append_to_archive(&mut file, archive_file, nothing) {
-			Err(why) => panic!("Failed to append file data from \"{}\" to archive_file: {}", entry.path.display(), why),
-			Ok(_) => ()
+		// Link to the file we already extracted, rather than writing its data again
+		if let EntryKind::Hardlink(ref target) = entry.kind {
+			if let Some(target_out) = extracted.get(target) {
+				sandbox.hard_link(target_out, &out)?;
+				continue;
+			}
+		}
+
+		write_entry(archive, index, &sandbox, &out, &mut buffer)?;
+		let entry = &archive.header.entries[index];
+		match entry.kind {
+			EntryKind::File => { extracted.insert(entry.path.clone(), out.clone()); },
+			// Other links to the same file can link to this copy, rather than reading the data again
+			EntryKind::Hardlink(ref target) => { extracted.entry(target.clone()).or_insert_with(|| out.clone()); },
+			_ => ()
+		}
+		if let Some(ref metadata) = entry.metadata {
+			if entry.kind == EntryKind::Directory {
+				directories.push((index, out));
+			} else {
+				sandbox.restore_metadata(&out, metadata, options)?;
+			}
+		}
+	};
+
+	// Directories come before their contents, so going backwards does the innermost first
+	for (index, out) in directories.into_iter().rev() {
+		if let Some(ref metadata) = archive.header.entries[index].metadata {
+			sandbox.restore_metadata(&out, metadata, options)?;
 		}
 	}
+
+	Ok(())
 }
 
-/// Appends a File `file` to the end of `archive_file`.
-/// A [`ByteOp`] can be passed to change the file data as it is copied
-fn append_to_archive(file: &mut File, archive_file: &mut File, compression: ByteOp) -> std::io::Result<()> {
-	let size = file.metadata()?.len();
-	let max_size = MAX_BUFFER_SIZE.try_into().expect(
-		&format!("Woah, you're running this on a >64 bit platform? Cool! It's broken. Try lowering your buffer size to something below {} bytes", u64::MAX));
-	let mut remaining_size = size;
+/// Puts `indices` in the order their entries' data comes in the archive, so it can all be read in one pass without
+/// going backwards. Entries that share a spot keep the order they're in the header, so directories still come
+/// before their contents and hard links after the files they link to
+fn data_order(entries: &[FileEntry], indices: &[usize]) -> Vec<usize> {
+	let mut ordered = indices.to_vec();
+	ordered.sort_by_key(|&index| (entries[index].offset, index));
+	ordered
+}
 
-	while remaining_size > max_size {
-		// Seek to the position of the next chunk. We do size - remaining because doing a 
-		// simple SeekFrom::End(size) doesn't work, as it wants an i64 rather than a u64
-		file.seek(SeekFrom::Start(size - remaining_size))?;
-		// Create a buffer and read into it
-		let mut buffer = vec![0u8; MAX_BUFFER_SIZE];
-		file.read_exact(&mut buffer)?;
+/// Extracts the entry at `index` in the archive's header into `out_path`, at the entry's path inside it.
+/// Symlinks and directories are recreated, while hard links are written out as a copy of the file they link to.
+/// The same rules as [`extract_all_archive`] apply, so the entry can't be written outside of `out_path`
+pub fn extract_entry<R: Read + Seek>(archive: &mut Archive<R>, index: usize, out_path: &Path, options: &ExtractOptions) -> Result<(), MpkError> {
+	extract_entries(archive, &[index], out_path, options)
+}
 
-		// Run the given compression function on the data pulled. If there is no compression the data doesn't change
-		buffer = compression(buffer);
+/// Writes out the entry at `index` inside `sandbox` at `out`, but leaves its metadata for the caller
+fn write_entry<R: Read + Seek>(archive: &mut Archive<R>, index: usize, sandbox: &Sandbox, out: &Path, buffer: &mut [u8]) -> Result<(), MpkError> {
+	let entry = &archive.header.entries[index];
 
-		// Seek to the end of the archive file and write the compressed data
-		archive_file.seek(SeekFrom::End(0))?;
-		archive_file.write(&buffer)?;
+	match entry.kind {
+		EntryKind::Symlink(ref target) => sandbox.symlink(target, out)?,
+		EntryKind::Directory => sandbox.create_dir(out)?,
+		EntryKind::File | EntryKind::Hardlink(_) => {
+			// Try to create the file
+			let transforms = archive.entry_transforms(entry)?;
+			let mut out_file = sandbox.create_file(out)?;
 
-		remaining_size -= max_size; // Decrease the size of the file remaining
+			buffered_copy(&mut archive.file, &mut out_file, archive.start + entry.offset, entry, &transforms, buffer)?;
+		}
 	}
 
-	// Do the same operations one more time for the either the last bytes, or for files already below
-	// the maximum buffer size
-	file.seek(SeekFrom::Start(size - remaining_size))?;
-	let mut buffer = vec![0u8; remaining_size.try_into().unwrap()]; // remaining_size should be less than MAX_BUFFER_SIZE (a usize), so it's guaranteed to fit into usize
-	file.read(&mut buffer)?;
-	buffer = compression(buffer);
-	archive_file.seek(SeekFrom::End(0))?;
-	archive_file.write(&buffer)?;
-
 	Ok(())
 }
 
-// Unpack functions ********************************************************
+/// Whether `path` is a plain relative path, which can be safely joined onto the directory it's extracted to
+fn is_safe_path(path: &Path) -> bool {
+	path.components().any(|c| matches!(c, Component::Normal(_)))
+		&& path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
 
-pub fn unpack_archive(mut file: File, out_path: &Path) -> std::io::Result<()> {
-	// Try to create the directory to extract to
-	match std::fs::create_dir_all(&out_path) {
-		Err(why) => {
-			println!("Failed to make directory \"{}\", skipping {}. {}", out_path.display(), out_path.display(), why); 
-		},
-		Ok(f) => f
-	};
+/// Makes sure neither an entry's path nor the path of the entry it hard links to could lead outside of the
+/// directory it's extracted to
+fn check_entry_path(entry: &FileEntry) -> Result<(), MpkError> {
+	if !is_safe_path(&entry.path) {
+		return Err(MpkError::PathEscape(entry.path.clone()));
+	}
+	match entry.kind {
+		EntryKind::Hardlink(ref target) if !is_safe_path(target) => Err(MpkError::PathEscape(target.clone())),
+		_ => Ok(())
+	}
+}
 
-	let mut archive = Archive {
-		header: read_header(&mut file),
-		file: file
+/// Copies the data of `entry`, which starts at `index` in `file`, to `output`,
+/// undoing each of `transforms` on the way and checking it against the entry's checksum. The data is copied through `buffer`
+fn buffered_copy<R: Read + Seek>(file: &mut R, output: &mut dyn Write, index: u64, entry: &FileEntry, transforms: &[Rc<dyn Transform>], buffer: &mut [u8]) -> Result<(), MpkError> {
+	file.seek(SeekFrom::Start(index))?;
+	let transforms: Vec<&dyn Transform> = transforms.iter().map(|transform| &**transform).collect();
+	let mut input = ChecksumReader {
+		inner: transform::stack_decoders(&transforms, Box::new(file.take(entry.stored_size))),
+		checksum: 0
 	};
-	
-	extract_all_archive(&mut archive, &out_path, nothing)?;
-	archive.file.sync_all()?;
-	
-	Ok(())
+
+	copy_exact(&mut input, output, entry.size, buffer)?;
+	match entry.checksum {
+		Some(checksum) if checksum != input.checksum => Err(MpkError::ChecksumMismatch(entry.path.clone())),
+		_ => Ok(())
+	}
 }
 
+/// Checks that every entry in `archive` can be decoded and matches its checksum, without writing anything out.
+/// Returns the path of each bad entry along with what's wrong with it, or fails if the archive itself can't be read
+pub fn verify_archive<R: Read + Seek>(archive: &mut Archive<R>) -> Result<Vec<(PathBuf, MpkError)>, MpkError> {
+	let mut bad_entries = Vec::new();
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut checked = HashSet::new(); // Hard links share their data with the file they link to, so it's only checked once
+	let all: Vec<usize> = (0..archive.header.entries.len()).collect();
+	for index in data_order(&archive.header.entries, &all) {
+		let entry = &archive.header.entries[index];
+		if entry.stored_size > 0 && !checked.insert((entry.offset, entry.stored_size)) {
+			continue;
+		}
+		let result = match archive.entry_transforms(entry) {
+			Err(why) => Err(why),
+			Ok(transforms) => buffered_copy(&mut archive.file, &mut std::io::sink(), archive.start + entry.offset, entry, &transforms, &mut buffer)
+		};
+		match result {
+			// Data that ends early or doesn't decode is the entry's fault, anything else means reading failed
+			Err(MpkError::Io(why)) if !matches!(why.kind(), std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData) => return Err(why.into()),
+			Err(why) => bad_entries.push((entry.path.clone(), why)),
+			Ok(()) => ()
+		}
+	}
 
-// Finds a file (path_in_archive) in an archive and copies it to (out_path)
-pub fn extract_from_archive(path_in_archive: &Path, archive: &mut Archive, mut out_file: &mut File, decompression: ByteOp) -> std::io::Result<()> {
-	let mut index = archive.header.size; // Start at the end of the header
+	Ok(bad_entries)
+}
+
+// Edit functions ********************************************************
+
+/// What [`Archive::append`] does with an entry whose path is already in the archive.
+/// Directories are merged whatever the policy, keeping the metadata of the one already there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+	Replace, // The old entry is dropped from the header, though its data stays where it was
+	KeepBoth, // Both entries are kept, and the new one wins when they're extracted
+	Error // Nothing is added, and MpkError::DuplicateEntry is returned
+}
 
-	for entry in &mut archive.header.entries {
-		if entry.path == *path_in_archive { // Once we find the entry,
-			buffered_copy(&mut archive.file, &mut out_file, &mut index, entry.size, decompression)?;
+impl<F: Read + Write + Seek> Archive<F> {
+	/// Adds the entries of `builder` to the archive. Their data is written to the end of the file and only the header
+	/// is rewritten, so nothing already in the archive is copied unless the header outgrows the space before the data.
+	/// Tags set on `builder` are added too, apart from reserved ones, so the archive keeps its original provenance.
+	/// If writing fails part way the archive is left as it was, with some unused data on the end.
+	/// Entries are written with `builder`'s transforms, which should match the ones the archive was packed with
+	///
+	/// # Examples
+	///
+	/// ```ignore
+	/// let mut archive = Archive::new(OpenOptions::new().read(true).write(true).open("assets.mpk")?)?;
+	/// let mut builder = ArchiveBuilder::new();
+	/// builder.add_path(Path::new("levels"))?;
+	/// archive.append(builder, DuplicatePolicy::Replace)?;
+	/// ```
+	pub fn append(&mut self, mut builder: ArchiveBuilder, policy: DuplicatePolicy) -> Result<(), MpkError> {
+		let mut pending = Vec::new();
+		let mut replaced = HashSet::new();
+		{
+			let existing: HashMap<&Path, bool> = self.header.entries.iter()
+				.map(|entry| (entry.path.as_path(), entry.kind == EntryKind::Directory))
+				.collect();
+			for new in builder.entries {
+				match existing.get(new.entry.path.as_path()) {
+					Some(&true) if new.entry.kind == EntryKind::Directory => continue,
+					Some(_) => match policy {
+						DuplicatePolicy::Replace => { replaced.insert(new.entry.path.clone()); },
+						DuplicatePolicy::KeepBoth => (),
+						DuplicatePolicy::Error => return Err(MpkError::DuplicateEntry(new.entry.path))
+					},
+					None => ()
+				}
+				pending.push(new);
+			}
 		}
+
+		self.file.seek(SeekFrom::End(0))?;
+		let written = write_entries(pending, &builder.transforms, builder.on_change, &mut *builder.on_warning, &mut self.file, self.start, &self.header.entries)?;
+
+		let mut entries: Vec<FileEntry> = self.header.entries.iter()
+			.filter(|entry| !replaced.contains(&entry.path))
+			.cloned()
+			.collect();
+		entries.extend(written);
+		let mut tags = self.header.tags.clone();
+		tags.extend(builder.tags.into_iter().filter(|(name, _)| !name.starts_with(RESERVED_TAG_PREFIX)));
+		self.rewrite(entries, tags)
 	}
 
-	Ok(())
-}
+	/// Removes the entries at `indices` from the archive. Only the header is rewritten, so their data is left
+	/// where it was until the archive is compacted with [`Archive::compact`]. Hard links to a removed file keep working
+	pub fn delete(&mut self, indices: &[usize]) -> Result<(), MpkError> {
+		let indices: HashSet<usize> = indices.iter().cloned().collect();
+		let entries = self.header.entries.iter().enumerate()
+			.filter(|(index, _)| !indices.contains(index))
+			.map(|(_, entry)| entry.clone())
+			.collect();
+		let tags = self.header.tags.clone();
+		self.rewrite(entries, tags)
+	}
 
-pub fn extract_all_archive(archive: &mut Archive, out_path: &Path, decompression: ByteOp) -> std::io::Result<()> {
-	let mut index = archive.header.size; // Start at the end of the header
-		
-	std::fs::create_dir_all(out_path)?;
+	/// Swaps entries of the archive for the entries of `builder` with the same paths, like [`Archive::append`] with
+	/// [`DuplicatePolicy::Replace`]. Every file in `builder` has to replace something, otherwise nothing is changed
+	pub fn replace(&mut self, builder: ArchiveBuilder) -> Result<(), MpkError> {
+		{
+			let existing: HashSet<&Path> = self.header.entries.iter().map(|entry| entry.path.as_path()).collect();
+			if let Some(missing) = builder.entries.iter().find(|pending| !existing.contains(pending.entry.path.as_path())) {
+				return Err(std::io::Error::new(std::io::ErrorKind::NotFound,
+					format!("\"{}\" isn't in the archive", missing.entry.path.display())).into());
+			}
+		}
+		self.append(builder, DuplicatePolicy::Replace)
+	}
 
-	for entry in &mut archive.header.entries {
-		let e_path = out_path.join(&entry.path);
+	/// Moves the data of the archive's entries together, reclaiming the space left behind by deleted and replaced
+	/// entries, and clears whatever was left between the header and the data. Returns where the archive now ends
+	/// in the file, which the file should be truncated to.
+	/// Everything is moved in place, so if this is interrupted the archive is likely to be left corrupt
+	pub fn compact(&mut self) -> Result<u64, MpkError> {
+		// Get the header into the current format first, which makes sure it fits in front of the data
+		// so that everything else only ever has to move towards the start of the file
+		if !self.header.trailing {
+			let entries = self.header.entries.clone();
+			let tags = self.header.tags.clone();
+			self.rewrite(entries, tags)?;
+		}
 
-		// Create directories for file
-		match e_path.parent() {
-			None => (),
-			Some(parent) => std::fs::create_dir_all(parent)?
+		// Entries can share data, so each run of data that's in use is moved as one
+		let mut extents: Vec<(u64, u64)> = self.header.entries.iter()
+			.filter(|entry| entry.stored_size > 0)
+			.map(|entry| (entry.offset, entry.offset + entry.stored_size))
+			.collect();
+		extents.sort_unstable();
+		let mut runs: Vec<(u64, u64)> = Vec::new();
+		for (from, to) in extents {
+			match runs.last_mut() {
+				Some(last) if from <= last.1 => last.1 = std::cmp::max(last.1, to),
+				_ => runs.push((from, to))
+			}
 		}
 
-		// Try to create the file
-		let mut out_file = match File::create(&e_path) {
-			Err(why) => {
-				println!("Unable to create file \"{}\", {}", e_path.display(), why);
-				continue;
-			},
-			Ok(file) => file 
+		let end = self.file.seek(SeekFrom::End(0))? - self.start;
+		let data_start = if self.header.trailing {
+			TRAILING_PREFIX_SIZE
+		} else {
+			std::cmp::min(runs.first().map_or(end, |run| run.0), self.header.size + HEADER_RESERVE)
 		};
+		let mut position = data_start;
+		let mut moved_to = Vec::with_capacity(runs.len());
+		for &(from, to) in &runs {
+			shift_back(&mut self.file, self.start + from, self.start + to, from - position)?;
+			moved_to.push(position);
+			position += to - from;
+		}
 
-		buffered_copy(&mut archive.file, &mut out_file, &mut index, entry.size, decompression)?;
-	};
+		let mut entries = self.header.entries.clone();
+		for entry in &mut entries {
+			// Entries without data are moved along with the data after them, so everything stays in the same order
+			let run = runs.partition_point(|run| run.1 <= entry.offset);
+			entry.offset = match runs.get(run) {
+				Some(&(from, _)) if from <= entry.offset => entry.offset - from + moved_to[run],
+				Some(_) => moved_to[run],
+				None => position
+			};
+		}
+		let tags = self.header.tags.clone();
+
+		// The index goes straight after the data. The old footer is still at the end of the file until it's truncated,
+		// so the header can't be read back from it
+		if self.header.trailing {
+			let header = Header { version: ARCHIVE_VERSION, entries, tags, size: 0, trailing: true };
+			self.file.seek(SeekFrom::Start(self.start + position))?;
+			let len = write_index(&mut self.file, position, &header)?;
+			self.file.flush()?;
+			self.header = Header { size: len - FOOTER_SIZE, ..header };
+			return Ok(self.start + position + len);
+		}
 
-	Ok(())
+		self.rewrite(entries, tags)?;
+		self.file.seek(SeekFrom::Start(self.start + self.header.size))?;
+		std::io::copy(&mut std::io::repeat(0).take(data_start - self.header.size), &mut self.file)?;
+		self.file.flush()?;
+		Ok(self.start + position)
+	}
+
+	/// Writes a header with `entries` and `tags` over the archive's, then reads it back, as rewriting it may have moved the data
+	fn rewrite(&mut self, entries: Vec<FileEntry>, tags: HashMap<String, String>) -> Result<(), MpkError> {
+		let header = Header { version: self.header.version, entries, tags, size: self.header.size, trailing: self.header.trailing };
+		rewrite_header(&mut self.file, self.start, header)?;
+		self.file.seek(SeekFrom::Start(self.start))?;
+		self.header = read_index(&mut self.file)?;
+		Ok(())
+	}
 }
 
-fn buffered_copy(file: &mut File, output: &mut dyn Write, index: &mut u64, size: u64, modify: ByteOp) -> std::io::Result<()> {
-	let max_size = MAX_BUFFER_SIZE.try_into().expect(
-		&format!("Woah, you're running this on a >64 bit platform? Cool! It's broken. Try lowering your buffer size to something below {} bytes", u64::MAX));
+/// Replaces the tags of the archive at the current position of `file` with `tags`.
+/// Only the header is rewritten if the new one fits in the space of the old one,
+/// otherwise the entry data is moved along to make room
+///
+/// # Examples
+///
+/// ```ignore
+/// let mut file = OpenOptions::new().read(true).write(true).open("assets.mpk")?;
+/// let mut tags = read_index(&mut file)?.tags;
+/// tags.insert(String::from("game"), String::from("Example"));
+/// file.seek(SeekFrom::Start(0))?;
+/// write_tags(&mut file, tags)?;
+/// ```
+pub fn write_tags<F: Read + Write + Seek>(file: &mut F, tags: HashMap<String, String>) -> Result<(), MpkError> {
+	let start = file.stream_position()?;
+	let mut header = read_index(file)?;
+	header.tags = tags;
+	rewrite_header(file, start, header)
+}
 
-	let mut remaining_size = size;
-	// If the file size is bigger than our buffer, split it up
-	while remaining_size > max_size {
-		// Seek to the position of the next chunk. We do size - remaining because doing a 
-		// simple SeekFrom::End(size) doesn't work, as it wants an i64 rather than a u64
-		file.seek(SeekFrom::Start(*index + (size - remaining_size) ))?;
-		let mut buffer = vec![0u8; MAX_BUFFER_SIZE];
-		file.read_exact(&mut buffer)?;
+/// Writes `header` over the header of the archive at `start` in `file`. The header is always written in the current
+/// format, into the space between `start` and the first entry's data. If it doesn't fit there, everything after
+/// that space is moved along to make room, with [`HEADER_RESERVE`] bytes spare for next time.
+/// Trailing indexes are written to the end of `file` instead
+fn rewrite_header<F: Read + Write + Seek>(file: &mut F, start: u64, mut header: Header) -> Result<(), MpkError> {
+	header.version = ARCHIVE_VERSION;
+	let end = file.seek(SeekFrom::End(0))?;
+
+	// A trailing index is never overwritten, the new one just goes on the end and the old one is left until compacting
+	if header.trailing {
+		write_index(file, end - start, &header)?;
+		file.flush()?;
+		return Ok(());
+	}
 
-		buffer = modify(buffer);
-		output.write(&buffer)?;
+	let mut data = gen_header(&header)?;
+	let room = header.entries.iter()
+		.filter(|entry| entry.stored_size > 0)
+		.map(|entry| entry.offset)
+		.min()
+		.unwrap_or(end - start);
+	let new_size = data.len() as u64;
+	if new_size > room {
+		let shift = new_size - room + HEADER_RESERVE;
+		shift_forward(file, start + room, end, shift)?;
+		for entry in &mut header.entries {
+			entry.offset += shift;
+		}
+		data = gen_header(&header)?;
 
-		remaining_size -= max_size;
+		// Clear what's left of the moved data between the header and where it's been moved to
+		file.seek(SeekFrom::Start(start + new_size))?;
+		std::io::copy(&mut std::io::repeat(0).take(room + shift - new_size), file)?;
 	}
 
-	// Do the same operations one more time for the either the last bytes, or for files already below
-	// the maximum buffer size
-	file.seek(SeekFrom::Start(*index + (size - remaining_size) ))?;
-	let mut buffer = vec![0u8; remaining_size.try_into().unwrap()]; // remaining_size should be less than MAX_BUFFER_SIZE (a usize), so it's guaranteed to fit into usize
-	file.read_exact(&mut buffer)?;
+	file.seek(SeekFrom::Start(start))?;
+	file.write_all(&data)?;
+	file.flush()?;
+	Ok(())
+}
 
-	buffer = modify(buffer);
-	output.write(&buffer)?;
+/// Moves the bytes of `file` from `from` up to `to` along by `shift` bytes, starting from the end so nothing
+/// is overwritten before it's been moved
+fn shift_forward<F: Read + Write + Seek>(file: &mut F, from: u64, to: u64, shift: u64) -> std::io::Result<()> {
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut end = to;
+	while end > from {
+		let len = std::cmp::min(end - from, buffer.len() as u64) as usize;
+		let chunk_start = end - len as u64;
+		file.seek(SeekFrom::Start(chunk_start))?;
+		file.read_exact(&mut buffer[..len])?;
+		file.seek(SeekFrom::Start(chunk_start + shift))?;
+		file.write_all(&buffer[..len])?;
+		end = chunk_start;
+	}
+	Ok(())
+}
 
-	*index += size; // Add each entry's size to the *index, which will give us the *index of the file data when we find it
-	
+/// Moves the bytes of `file` from `from` up to `to` back by `shift` bytes, starting from the start so nothing
+/// is overwritten before it's been moved
+fn shift_back<F: Read + Write + Seek>(file: &mut F, from: u64, to: u64, shift: u64) -> std::io::Result<()> {
+	if shift == 0 {
+		return Ok(());
+	}
+	let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+	let mut start = from;
+	while start < to {
+		let len = std::cmp::min(to - start, buffer.len() as u64) as usize;
+		file.seek(SeekFrom::Start(start))?;
+		file.read_exact(&mut buffer[..len])?;
+		file.seek(SeekFrom::Start(start - shift))?;
+		file.write_all(&buffer[..len])?;
+		start += len as u64;
+	}
 	Ok(())
 }
 
-// Expands multiple root paths while checking for duplicates
-fn expand_paths(input_paths: &Vec<PathBuf>) -> Vec<PathBuf> {
-	let mut output_paths: Vec<PathBuf> = Vec::new(); // Make a vec of pathbufs to return
+/// Passes reads through to `inner`, keeping a checksum of everything that's been read
+struct ChecksumReader<R> {
+	inner: R,
+	checksum: u32
+}
 
-	for in_path in input_paths { // For each input path given to us,
-		match expand_path(&in_path) { // walk its path tree and check for errors
-			Err(why) => println!("Unable to follow path tree with root \"{}\": {}", in_path.display(), why),
-			Ok(tree_paths) => {
-				for p in tree_paths { 
-					if !output_paths.contains(&p) { // Check for duplicates
-						output_paths.push(p);
-					}
-				} 
-			}, 
+impl<R: Read> Read for ChecksumReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let read = self.inner.read(buf)?;
+		self.checksum = crc32c::crc32c_append(self.checksum, &buf[..read]);
+		Ok(read)
+	}
+}
+
+/// Copies exactly `size` bytes from `input` to `output`, a buffer's worth at a time.
+/// Fails with `UnexpectedEof` if `input` runs out first
+fn copy_exact<R: Read + ?Sized, W: Write + ?Sized>(input: &mut R, output: &mut W, size: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+	let mut remaining = size;
+	while remaining > 0 {
+		let len = std::cmp::min(remaining, buffer.len() as u64) as usize;
+		let read = match input.read(&mut buffer[..len]) {
+			Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+				format!("Data ended {} bytes early", remaining))),
+			Ok(read) => read,
+			Err(ref why) if why.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(why) => return Err(why)
 		};
+		output.write_all(&buffer[..read])?;
+		remaining -= read as u64;
 	}
 
-	output_paths
+	Ok(())
 }
 
 // Takes a vec of strings and returns a vec of PathBufs
@@ -389,70 +2059,111 @@ pub fn strings_to_paths(strings: Vec<String>) -> Vec<PathBuf> {
 	paths
 }
 
-// Returns a vec of tuples, (path, file_size). The files left in <paths> are the paths that failed the metadata check and are not in the output
-fn get_file_sizes(paths: Vec<PathBuf>) -> Vec<FileEntry> {
+// Returns an entry for each path, along with its filesystem metadata. Paths that failed the metadata check are left out,
+// and added to `skipped`. Symlinks are returned as links unless `dereference` is set
+fn get_file_sizes(paths: Vec<PathBuf>, dereference: bool, skipped: &mut Vec<MpkError>) -> Vec<(FileEntry, std::fs::Metadata)> {
 	let mut out = Vec::new();
 	for path in paths {
-		let size = match path.metadata() { // Try to get the metadata
+		let metadata = if dereference { path.metadata() } else { path.symlink_metadata() };
+		let metadata = match metadata { // Try to get the metadata
 			Err(why) => {
-				println!("Failed to get metadata from \"{}\" because: {}, skipping file.", path.display(), why);
+				skipped.push(MpkError::UnreadablePath(path, why));
 				continue;
 			},
 			// Sucessfully got metadata
-			Ok(metadata) => metadata.len()
+			Ok(metadata) => metadata
+		};
+
+		let (kind, size) = if metadata.is_dir() {
+			(EntryKind::Directory, 0)
+		} else if metadata.file_type().is_symlink() {
+			match std::fs::read_link(&path) {
+				Err(why) => {
+					skipped.push(MpkError::UnreadablePath(path, why));
+					continue;
+				},
+				Ok(target) => (EntryKind::Symlink(target), 0)
+			}
+		} else {
+			(EntryKind::File, metadata.len())
 		};
 
-		out.push(FileEntry { path: path, size: size });
+		// Where the data goes is filled in once it's written
+		let entry = FileEntry {
+			path,
+			size,
+			offset: 0,
+			stored_size: 0,
+			codec: Store.id(),
+			checksum: None,
+			metadata: Some(Metadata::from_fs(&metadata)),
+			kind,
+			changed: false
+		};
+		out.push((entry, metadata));
 	}
 
 	out
 }
 
 /// Creates a Vec<u8> consisting of the size of (string) as a u64(little endian), and the string as bytes
-/// 
+///
 /// # Examples
-/// 
-/// ```
+///
+/// ```ignore
 /// let b_string = sized_bit_string("Hello");
 /// assert_eq!(b_string, vec![5,0,0,0,0,0,0,0,72,101,108,108,111]);
-/// //					    ^----size-----^  ^----"Hello"----^
+/// //                        ^----size-----^  ^----"Hello"----^
 /// ```
-fn sized_bit_string(string: &String) -> Vec<u8> {
+fn sized_bit_string(string: &str) -> Vec<u8> {
 	let mut buffer: Vec<u8> = Vec::new();
-	buffer.write(&(string.len() as u64).to_le_bytes()).expect("couldn't write string length to buffer");
-	buffer.write(string.as_bytes()).expect("couldn't write string to buffer");
-	return buffer;
+	buffer.extend_from_slice(&(string.len() as u64).to_le_bytes());
+	buffer.extend_from_slice(string.as_bytes());
+	buffer
+}
+
+/// From a buffer, reads a u8 starting from (index), and adds its size to (index)
+fn read_u8(buffer: &[u8], index: &mut usize) -> Result<u8, MpkError> {
+	let byte = *buffer.get(*index).ok_or(MpkError::TruncatedHeader)?;
+	*index += size_of::<u8>();
+	Ok(byte)
+}
+
+/// From a buffer, reads a u64 (little endian) starting from (index), and adds its size to (index)
+fn read_u64(buffer: &[u8], index: &mut usize) -> Result<u64, MpkError> {
+	let bytes = buffer.get(*index..index.saturating_add(size_of::<u64>())).ok_or(MpkError::TruncatedHeader)?;
+	*index += size_of::<u64>();
+	// The slice is always 8 bytes long, so this can't fail
+	Ok(u64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+/// From a buffer, reads a u32 (little endian) starting from (index), and adds its size to (index)
+fn read_u32(buffer: &[u8], index: &mut usize) -> Result<u32, MpkError> {
+	let bytes = buffer.get(*index..index.saturating_add(size_of::<u32>())).ok_or(MpkError::TruncatedHeader)?;
+	*index += size_of::<u32>();
+	// The slice is always 4 bytes long, so this can't fail
+	Ok(u32::from_le_bytes(bytes.try_into().unwrap_or_default()))
 }
 
-/// From a buffer, reads a length (u64 little endian) in and returns a string from the length of bytes behind it,
-/// starting from (index), and adds the length read to (index) 
-/// Returns an empty string if it cant get the string's contents
-/// 
+/// From a buffer, reads a length (u64 little endian) in and returns the bytes behind it,
+/// starting from (index), and adds the length read to (index)
+/// Returns [`MpkError::TruncatedHeader`] if the buffer ends before the bytes do
+///
 /// # Examples
-/// 
-/// ```
+///
+/// ```ignore
 /// let buffer = vec![0,0,0,5,0,0,0,0,0,0,0,72,101,108,108,111,0,0,0];
-/// //					  ^----size-----^  ^----"Hello"----^
-/// assert_eq!(read_sized_bit_string(buffer, 3), "Hello");
+/// //                      ^----size-----^  ^----"Hello"----^
+/// assert_eq!(read_sized_bytes(&buffer, &mut 3)?, b"Hello");
 /// ```
-fn read_sized_bit_string(buffer: &Vec<u8>, index: &mut usize) -> String {
-	let len: usize = u64::from_le_bytes(
-		buffer[*index..*index + size_of::<u64>()] // Take a slice of the buffer, from start_byte to the end of a u64
-		.try_into().expect("slice for [string length] was wrong length, should have been 4 bytes") // Try to turn it into a 4 element array, if not, error
-	) as usize;
-	*index += size_of::<u64>();
+fn read_sized_bytes(buffer: &[u8], index: &mut usize) -> Result<Vec<u8>, MpkError> {
+	let len: usize = read_u64(buffer, index)?.try_into().map_err(|_| MpkError::TruncatedHeader)?;
+	let end = index.checked_add(len).ok_or(MpkError::TruncatedHeader)?;
 
-	let mut contents = Vec::new();
-	contents.extend(&buffer[*index..*index + len]);
-	*index += len;
+	let contents = buffer.get(*index..end).ok_or(MpkError::TruncatedHeader)?.to_vec();
+	*index = end;
 
-	return match String::from_utf8(contents) {
-		Err(why) => {
-			eprintln!("{}", why);
-			String::new()
-		},
-		Ok(string) => string
-	}
+	Ok(contents)
 }
 
 
@@ -475,6 +2186,13 @@ mod tests {
 		Ok(f)
 	}
 
+	// Extracts the entry at `path` in `archive` into memory
+	fn read<R: Read + Seek>(archive: &mut Archive<R>, path: &str) -> Result<Vec<u8>, MpkError> {
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new(path), archive, &mut extracted)?;
+		Ok(extracted)
+	}
+
 	fn compare_files(path1: &str, path2: &str) -> std::io::Result<bool> {
 		let mut file1 = match File::open(path1) {
 			Err(why) => panic!("Unable to open {}: {}", path1, why),
@@ -499,7 +2217,7 @@ mod tests {
 	}
 
 	#[test]
-	fn basic_archive_test() -> std::io::Result<()> {
+	fn basic_archive_test() -> Result<(), MpkError> {
 		create_test_file("pack_test/1.txt", b"Some test data".to_vec())?;
 		create_test_file("pack_test/folder/2.txt", b"Some more test data".to_vec())?;
 		create_test_file("pack_test/other_folder/folder/3.txt", b"Different test data".to_vec())?;
@@ -518,14 +2236,14 @@ mod tests {
 
 		// let paths = strings_to_paths(str_paths);
 
-		pack_archive(&mut file, &vec!(PathBuf::from("pack_test")), tags);
+		pack_archive(&mut file, &[PathBuf::from("pack_test")], tags)?;
 		file.flush()?;
 
 		let unpack_file = match File::open(&out_path) {
 			Err(why) => panic!("Unable to create {}: {}", out_path.display(), why),
 			Ok(file) => file,
 		};
-		unpack_archive(unpack_file, &PathBuf::from("unpack_test"))?;
+		unpack_archive(unpack_file, &PathBuf::from("unpack_test"), &ExtractOptions::default())?;
 
 		compare_files("unpack_test/1.txt", "pack_test/1.txt")?;
 		compare_files("unpack_test/folder/2.txt", "pack_test/folder/2.txt")?;
@@ -533,7 +2251,1095 @@ mod tests {
 
 		std::fs::remove_dir_all("pack_test")?;
 		std::fs::remove_dir_all("unpack_test")?;
+		std::fs::remove_file(&out_path)?;
+
+		Ok(())
+	}
+
+	#[test]
+	fn in_memory_archive_test() -> Result<(), MpkError> {
+		create_test_file("memory_test/1.txt", b"Some test data".to_vec())?;
+		create_test_file("memory_test/folder/2.txt", b"Some more test data".to_vec())?;
+
+		// Pack into a buffer that already has some unrelated data at the front
+		let mut buffer = std::io::Cursor::new(b"Not part of the archive".to_vec());
+		buffer.seek(SeekFrom::End(0))?;
+		pack_archive(&mut buffer, &[PathBuf::from("memory_test")], HashMap::new())?;
+		std::fs::remove_dir_all("memory_test")?;
+
+		buffer.seek(SeekFrom::Start(b"Not part of the archive".len() as u64))?;
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.entries.len(), 3); // Two files and the folder
+
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("folder/2.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, b"Some more test data");
+
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("1.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, b"Some test data");
+
+		Ok(())
+	}
+
+	#[test]
+	fn builder_test() -> Result<(), MpkError> {
+		create_test_file("builder_test/on_disk.txt", b"From a file".to_vec())?;
+
+		let streamed = b"From a reader, with some extra bytes";
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_file_as(Path::new("builder_test/on_disk.txt"), Path::new("renamed/file.txt"))?
+			.add_bytes(Path::new("bytes.txt"), b"From memory".to_vec())
+			.add_reader(Path::new("reader.txt"), &streamed[..], 13)
+			.set_tag("purpose", "testing")
+			.record_command(&[String::from("micropak-rs"), String::from("pack"), String::from("two words")]);
+		builder.finish(&mut buffer)?;
+		std::fs::remove_dir_all("builder_test")?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.tags.get("purpose").map(String::as_str), Some("testing"));
+		assert_eq!(archive.header.tags.get(TAG_WRITER_VERSION).map(String::as_str), Some(VERSION));
+		assert!(archive.header.tags.contains_key(TAG_CREATED));
+		assert_eq!(archive.header.tags.get(TAG_COMMAND).map(String::as_str), Some("micropak-rs pack \"two words\""));
+
+		for (path, expected) in [("renamed/file.txt", &b"From a file"[..]), ("bytes.txt", b"From memory"), ("reader.txt", b"From a reader")] {
+			let mut extracted = Vec::new();
+			extract_from_archive(Path::new(path), &mut archive, &mut extracted)?;
+			assert_eq!(extracted, expected);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn entries_test() -> Result<(), MpkError> {
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("first.txt"), b"First entry".to_vec())
+			.add_bytes(Path::new("second.txt"), b"Second entry".to_vec());
+		builder.finish(&mut buffer)?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		let mut entries: Vec<_> = archive.entries().collect();
+		assert_eq!(entries.len(), 2);
+
+		// Reading one entry doesn't disturb another, and neither reads past its own end
+		let mut contents = String::new();
+		entries[1].seek(SeekFrom::Start(7))?;
+		entries[0].read_to_string(&mut contents)?;
+		assert_eq!(contents, "First entry");
+
+		contents.clear();
+		entries[1].read_to_string(&mut contents)?;
+		assert_eq!(contents, "entry");
+		assert_eq!(entries[1].entry.path, Path::new("second.txt"));
+
+		assert!(entries[0].seek(SeekFrom::Current(-100)).is_err());
+		assert_eq!(entries[0].seek(SeekFrom::End(-5))?, 6);
+
+		Ok(())
+	}
+
+	#[test]
+	fn compressed_archive_test() -> Result<(), MpkError> {
+		let repetitive: Vec<u8> = b"All work and no play makes Jack a dull boy. ".iter().cycle().take(100_000).cloned().collect();
+		create_test_file("compressed_test/in/dull.txt", repetitive.clone())?;
+
+		let mut file = File::create("compressed_test/test.mpk")?;
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("stored.txt"), b"Stored as is".to_vec())
+			.set_codec(Box::new(codec::Lz4))
+			.add_path(Path::new("compressed_test/in"))?;
+		builder.finish(&mut file)?;
+
+		let mut archive = Archive::new(File::open("compressed_test/test.mpk")?)?;
+		let entry = &archive.header.entries[1];
+		assert_eq!(entry.codec, codec::Lz4.id());
+		assert!(entry.stored_size < entry.size / 10);
+
+		extract_all_archive(&mut archive, Path::new("compressed_test/out"), &ExtractOptions::default())?;
+		assert_eq!(std::fs::read("compressed_test/out/dull.txt")?, repetitive);
+		assert_eq!(std::fs::read("compressed_test/out/stored.txt")?, b"Stored as is");
+
+		// Seeking around inside a compressed entry decodes up to wherever it lands
+		let mut entries: Vec<_> = archive.entries().collect();
+		let mut contents = [0u8; 4];
+		entries[1].seek(SeekFrom::Start(70_008))?;
+		entries[1].read_exact(&mut contents)?;
+		assert_eq!(&contents, b"work");
+		entries[1].seek(SeekFrom::Current(-8))?;
+		entries[1].read_exact(&mut contents)?;
+		assert_eq!(&contents, b"All ");
+
+		std::fs::remove_dir_all("compressed_test")?;
+		Ok(())
+	}
+
+	#[test]
+	fn transform_archive_test() -> Result<(), MpkError> {
+		let data: Vec<u8> = b"Transformed ".iter().cycle().take(100_000).cloned().collect();
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		// Any codec can also be used as a transform, it just won't be recorded in the header
+		builder.add_transform(Box::new(codec::Lz4))
+			.add_bytes(Path::new("data.txt"), data.clone());
+		builder.finish(&mut buffer)?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert!(archive.header.entries[0].stored_size < data.len() as u64);
+		archive.set_transform(Box::new(codec::Lz4));
+
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("data.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, data);
+
+		let mut contents = Vec::new();
+		let mut entry = archive.entries().next().unwrap();
+		entry.seek(SeekFrom::Start(12))?;
+		entry.read_to_end(&mut contents)?;
+		assert_eq!(contents, &data[12..]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn streaming_copy_test() -> Result<(), MpkError> {
+		// Bigger than the copy buffer and not a multiple of it, generated on the fly so the test doesn't hold it all either
+		let size = 3 * COPY_BUFFER_SIZE as u64 + 5;
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_codec(Box::new(codec::Lz4))
+			.add_reader(Path::new("big"), std::io::repeat(b'x').take(size), size);
+		builder.finish(&mut buffer)?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		let mut entry = archive.entries().next().unwrap();
+		let mut chunk = [0u8; 1000];
+		let mut copied = 0;
+		loop {
+			let read = entry.read(&mut chunk)?;
+			if read == 0 {
+				break;
+			}
+			assert!(chunk[..read].iter().all(|&byte| byte == b'x'));
+			copied += read as u64;
+		}
+		assert_eq!(copied, size);
+
+		// A reader that ends before its stated length is an error, rather than a short entry
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_reader(Path::new("short"), &b"Too short"[..], 100);
+		match builder.finish(&mut buffer) {
+			Err(MpkError::Io(why)) => assert_eq!(why.kind(), std::io::ErrorKind::UnexpectedEof),
+			other => panic!("Expected an UnexpectedEof error, got {:?}", other)
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn checksum_test() -> Result<(), MpkError> {
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("good.txt"), b"Left alone".to_vec())
+			.add_bytes(Path::new("bad.txt"), b"Gets a bit flipped".to_vec());
+		builder.finish(&mut buffer)?;
+
+		let mut data = buffer.into_inner();
+		let bad_offset = {
+			let archive = Archive::new(std::io::Cursor::new(&data))?;
+			archive.header.entries[1].offset as usize
+		};
+		data[bad_offset + 3] ^= 0b100;
+
+		let mut archive = Archive::new(std::io::Cursor::new(&data))?;
+		let bad_entries = verify_archive(&mut archive)?;
+		assert_eq!(bad_entries.len(), 1);
+		assert_eq!(bad_entries[0].0, Path::new("bad.txt"));
+		assert!(matches!(bad_entries[0].1, MpkError::ChecksumMismatch(_)));
+
+		let result = extract_from_archive(Path::new("bad.txt"), &mut archive, &mut Vec::new());
+		assert!(matches!(result, Err(MpkError::ChecksumMismatch(_))));
+
+		// Reading an entry all the way through checks it too
+		let mut entries = archive.entries();
+		let mut contents = Vec::new();
+		entries.next().unwrap().read_to_end(&mut contents)?;
+		assert_eq!(contents, b"Left alone");
+		let result = entries.next().unwrap().read_to_end(&mut contents);
+		assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+		// Any change to the header is caught before it's parsed
+		data[20] ^= 1;
+		assert!(matches!(Archive::new(std::io::Cursor::new(&data)), Err(MpkError::HeaderChecksumMismatch)));
+
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn metadata_archive_test() -> Result<(), MpkError> {
+		use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+		create_test_file("metadata_test/in/script.sh", b"#!/bin/sh".to_vec())?;
+		let original = Metadata {
+			mode: 0o751,
+			modified: Timestamp { secs: 1_234_567_890, nanos: 123_456_789 },
+			accessed: Timestamp { secs: 1_300_000_000, nanos: 5 },
+			..Metadata::from_fs(&std::fs::metadata("metadata_test/in/script.sh")?)
+		};
+		metadata::restore(Path::new("metadata_test/in/script.sh"), &original, &ExtractOptions::default())?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		pack_archive(&mut buffer, &[PathBuf::from("metadata_test/in")], HashMap::new())?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.entries[0].metadata.as_ref(), Some(&original));
+
+		extract_all_archive(&mut archive, Path::new("metadata_test/out"), &ExtractOptions::default())?;
+		let extracted = std::fs::metadata("metadata_test/out/script.sh")?;
+		assert_eq!(extracted.permissions().mode() & 0o7777, 0o751);
+		assert_eq!((extracted.mtime(), extracted.mtime_nsec()), (1_234_567_890, 123_456_789));
+		assert_eq!((extracted.atime(), extracted.atime_nsec()), (1_300_000_000, 5));
+
+		// Skipping permissions leaves the extracted file with the default ones
+		let options = ExtractOptions { restore_permissions: false, ..ExtractOptions::default() };
+		std::fs::remove_dir_all("metadata_test/out")?;
+		extract_all_archive(&mut archive, Path::new("metadata_test/out"), &options)?;
+		assert_ne!(std::fs::metadata("metadata_test/out/script.sh")?.permissions().mode() & 0o7777, 0o751);
+
+		std::fs::remove_dir_all("metadata_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn special_bits_test() -> Result<(), MpkError> {
+		use std::os::unix::fs::PermissionsExt;
+
+		create_test_file("special_bits_test/in/setuid", b"#!/bin/sh".to_vec())?;
+		std::fs::set_permissions("special_bits_test/in/setuid", std::fs::Permissions::from_mode(0o4755))?;
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		pack_archive(&mut buffer, &[PathBuf::from("special_bits_test/in")], HashMap::new())?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.entries[0].metadata.as_ref().unwrap().mode, 0o4755);
+
+		// Only root keeps them by default, as anyone else extracting an archive probably didn't make it
+		assert_eq!(ExtractOptions::default().restore_special_bits, metadata::is_privileged());
+		let options = ExtractOptions { restore_special_bits: false, ..ExtractOptions::default() };
+		extract_all_archive(&mut archive, Path::new("special_bits_test/out"), &options)?;
+		assert_eq!(std::fs::metadata("special_bits_test/out/setuid")?.permissions().mode() & 0o7777, 0o755);
+
+		let options = ExtractOptions { restore_special_bits: true, ..ExtractOptions::default() };
+		std::fs::remove_dir_all("special_bits_test/out")?;
+		extract_all_archive(&mut archive, Path::new("special_bits_test/out"), &options)?;
+		assert_eq!(std::fs::metadata("special_bits_test/out/setuid")?.permissions().mode() & 0o7777, 0o4755);
+
+		std::fs::remove_dir_all("special_bits_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn links_archive_test() -> Result<(), MpkError> {
+		use std::os::unix::fs::MetadataExt;
+
+		create_test_file("links_test/in/data.txt", b"Linked data".to_vec())?;
+		std::fs::hard_link("links_test/in/data.txt", "links_test/in/hard.txt")?;
+		std::os::unix::fs::symlink("data.txt", "links_test/in/soft.txt")?;
+		std::os::unix::fs::symlink(".", "links_test/in/loop")?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_path(Path::new("links_test/in"))?;
+		builder.finish(&mut buffer)?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.entries.len(), 4);
+		// The hard linked pair is only stored once, whichever was found first
+		let files: Vec<_> = archive.header.entries.iter().filter(|entry| entry.kind == EntryKind::File).collect();
+		assert_eq!(files.len(), 1);
+		let soft = archive.header.entries.iter().find(|entry| entry.path == Path::new("soft.txt")).unwrap();
+		assert_eq!(soft.kind, EntryKind::Symlink(PathBuf::from("data.txt")));
+
+		extract_all_archive(&mut archive, Path::new("links_test/out"), &ExtractOptions::default())?;
+		let data = std::fs::metadata("links_test/out/data.txt")?;
+		let hard = std::fs::metadata("links_test/out/hard.txt")?;
+		assert_eq!(data.ino(), hard.ino());
+		assert_eq!(std::fs::read_link("links_test/out/soft.txt")?, Path::new("data.txt"));
+		assert_eq!(std::fs::read_link("links_test/out/loop")?, Path::new("."));
+		assert_eq!(std::fs::read("links_test/out/hard.txt")?, b"Linked data");
+
+		// Following links stores copies instead, and skips the loop rather than going around it forever
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut warnings = Vec::new();
+		let mut builder = ArchiveBuilder::new();
+		builder.set_warning_handler(|warning| warnings.push(warning));
+		builder.set_dereference(true).add_path(Path::new("links_test/in"))?;
+		builder.finish(&mut buffer)?;
+		assert!(matches!(warnings[..], [MpkError::LinkLoop(ref path)] if path.ends_with("loop")));
+
+		buffer.set_position(0);
+		let archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.entries.len(), 3);
+		assert!(archive.header.entries.iter().all(|entry| entry.size == 11 && !matches!(entry.kind, EntryKind::Symlink(_))));
+
+		std::fs::remove_dir_all("links_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn directories_archive_test() -> Result<(), MpkError> {
+		use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+		create_test_file("directories_test/in/logs/today.log", b"Nothing happened".to_vec())?;
+		std::fs::create_dir_all("directories_test/in/tmp/empty")?;
+		let tmp_metadata = Metadata {
+			mode: 0o700,
+			modified: Timestamp { secs: 1_000_000_000, nanos: 0 },
+			..Metadata::from_fs(&std::fs::metadata("directories_test/in/tmp")?)
+		};
+		metadata::restore(Path::new("directories_test/in/tmp"), &tmp_metadata, &ExtractOptions::default())?;
+		let logs_mtime = Timestamp { secs: 1_100_000_000, nanos: 0 };
+		let logs_metadata = Metadata { modified: logs_mtime, ..Metadata::from_fs(&std::fs::metadata("directories_test/in/logs")?) };
+		metadata::restore(Path::new("directories_test/in/logs"), &logs_metadata, &ExtractOptions::default())?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		pack_archive(&mut buffer, &[PathBuf::from("directories_test/in")], HashMap::new())?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		let directories = archive.header.entries.iter().filter(|entry| entry.kind == EntryKind::Directory).count();
+		assert_eq!(directories, 3);
+
+		extract_all_archive(&mut archive, Path::new("directories_test/out"), &ExtractOptions::default())?;
+		assert!(Path::new("directories_test/out/tmp/empty").is_dir());
+		let tmp = std::fs::metadata("directories_test/out/tmp")?;
+		assert_eq!(tmp.permissions().mode() & 0o7777, 0o700);
+		assert_eq!(tmp.mtime(), 1_000_000_000);
+		// Writing the log into logs/ happened before its mtime was restored
+		assert_eq!(std::fs::metadata("directories_test/out/logs")?.mtime(), logs_mtime.secs);
+
+		std::fs::remove_dir_all("directories_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn path_escape_test() -> Result<(), MpkError> {
+		std::fs::create_dir_all("escape_test/outside")?;
+
+		// A symlink pointing out of the extraction directory, then a file written through it
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_symlink(Path::new("link"), &std::fs::canonicalize("escape_test/outside")?);
+		builder.add_bytes(Path::new("link/evil.txt"), b"Escaped".to_vec());
+		builder.finish(&mut buffer)?;
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert!(extract_all_archive(&mut archive, Path::new("escape_test/out"), &ExtractOptions::default()).is_err());
+		assert!(extract_entry(&mut archive, 1, Path::new("escape_test/out"), &ExtractOptions::default()).is_err());
+		assert!(!Path::new("escape_test/outside/evil.txt").exists());
+
+		// A version 1 archive holding "../evil.txt", which the builder wouldn't write
+		let mut data = vec![1, 52, 0, 0, 0, 0, 0, 0, 0];
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // No tags
+		data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]); // One entry
+		data.extend_from_slice(&[4, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend(sized_bit_string("../evil.txt"));
+		data.extend_from_slice(b"evil");
+
+		let mut archive = Archive::new(std::io::Cursor::new(data))?;
+		let result = extract_all_archive(&mut archive, Path::new("escape_test/out"), &ExtractOptions::default());
+		assert!(matches!(result, Err(MpkError::PathEscape(_))));
+		let result = extract_entry(&mut archive, 0, Path::new("escape_test/out"), &ExtractOptions::default());
+		assert!(matches!(result, Err(MpkError::PathEscape(_))));
+		assert!(!Path::new("escape_test/evil.txt").exists());
+
+		// The builder refuses to write paths like that in the first place
+		for path in &["/absolute.txt", "../parent.txt", "folder/../../parent.txt"] {
+			let mut builder = ArchiveBuilder::new();
+			builder.add_bytes(Path::new(path), b"data".to_vec());
+			let result = builder.finish(&mut std::io::Cursor::new(Vec::new()));
+			assert!(matches!(result, Err(MpkError::PathEscape(_))), "{} was packed", path);
+		}
+
+		std::fs::remove_dir_all("escape_test")?;
+		Ok(())
+	}
+
+	#[test]
+	fn write_tags_test() -> Result<(), MpkError> {
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_tag("keep", "this").set_tag("shrink", "a fairly long value to make room");
+		builder.add_bytes(Path::new("a.txt"), b"First".to_vec());
+		builder.add_bytes(Path::new("b.txt"), b"Second".to_vec());
+		builder.finish(&mut buffer)?;
+		let original_len = buffer.get_ref().len();
+
+		// Shrinking fits in the old header, so the data stays where it is
+		let mut tags = read_header(&mut std::io::Cursor::new(buffer.get_ref()))?.tags;
+		tags.insert(String::from("shrink"), String::from("short"));
+		buffer.set_position(0);
+		write_tags(&mut buffer, tags.clone())?;
+		assert_eq!(buffer.get_ref().len(), original_len);
+
+		// Growing into the space reserved after the header doesn't move anything either
+		tags.insert(String::from("grow"), "x".repeat(1000));
+		buffer.set_position(0);
+		write_tags(&mut buffer, tags.clone())?;
+		assert_eq!(buffer.get_ref().len(), original_len);
+
+		// Growing past it moves the data along
+		tags.insert(String::from("grow"), "x".repeat(2 * HEADER_RESERVE as usize));
+		tags.remove("keep");
+		buffer.set_position(0);
+		write_tags(&mut buffer, tags.clone())?;
+		assert!(buffer.get_ref().len() > original_len);
+
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(archive.header.tags, tags);
+		assert!(verify_archive(&mut archive)?.is_empty());
+		for (path, expected) in [("a.txt", &b"First"[..]), ("b.txt", b"Second")] {
+			let mut extracted = Vec::new();
+			extract_from_archive(Path::new(path), &mut archive, &mut extracted)?;
+			assert_eq!(extracted, expected);
+		}
+
+		// Version 1 archives get upgraded, as their data has to move to make room for the new fields
+		let mut data = vec![1, 46, 0, 0, 0, 0, 0, 0, 0];
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // No tags
+		data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]); // One entry
+		data.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend(sized_bit_string("a.txt"));
+		data.extend_from_slice(b"aaa");
+		let mut file = std::io::Cursor::new(data);
+		let mut tags = HashMap::new();
+		tags.insert(String::from("added"), String::from("later"));
+		write_tags(&mut file, tags)?;
+
+		file.set_position(0);
+		let mut archive = Archive::new(file)?;
+		assert_eq!(archive.header.version(), ARCHIVE_VERSION);
+		assert_eq!(archive.header.tags.get("added").map(String::as_str), Some("later"));
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("a.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, b"aaa");
+
+		Ok(())
+	}
+
+	#[test]
+	fn filter_archive_test() -> Result<(), MpkError> {
+		create_test_file("filter_test/src/main.rs", b"fn main() {}".to_vec())?;
+		create_test_file("filter_test/src/main.rs.swp", b"Swap file".to_vec())?;
+		create_test_file("filter_test/target/debug/out", b"Build output".to_vec())?;
+		create_test_file("filter_test/docs/notes.txt", b"Notes".to_vec())?;
+		create_test_file("filter_test/docs/draft.log", b"Draft".to_vec())?;
+		create_test_file("filter_test/docs/keep.log", b"Kept".to_vec())?;
+		create_test_file("filter_test/.gitignore", b"target/\n*.log\n".to_vec())?;
+		create_test_file("filter_test/docs/.mpkignore", b"!keep.log\n".to_vec())?;
+
+		let paths = |builder: ArchiveBuilder| -> Result<Vec<PathBuf>, MpkError> {
+			let mut buffer = std::io::Cursor::new(Vec::new());
+			builder.finish(&mut buffer)?;
+			buffer.set_position(0);
+			let mut paths: Vec<PathBuf> = Archive::new(buffer)?.header.entries.into_iter().map(|entry| entry.path).collect();
+			paths.sort();
+			Ok(paths)
+		};
+
+		let mut filter = PathFilter::new();
+		filter.exclude.push(pattern::Pattern::new("*.swp")?);
+		let mut builder = ArchiveBuilder::new();
+		builder.set_filter(filter).set_ignore_files(&pattern::DEFAULT_IGNORE_FILES).add_path(Path::new("filter_test"))?;
+		let expected: Vec<PathBuf> = [".gitignore", "docs", "docs/.mpkignore", "docs/keep.log", "docs/notes.txt", "src", "src/main.rs"]
+			.iter().map(PathBuf::from).collect();
+		assert_eq!(paths(builder)?, expected);
+
+		// Directories with nothing included inside them are left out
+		let mut filter = PathFilter::new();
+		filter.include.push(pattern::Pattern::new("*.rs")?);
+		let mut builder = ArchiveBuilder::new();
+		builder.set_filter(filter).add_path(Path::new("filter_test"))?;
+		assert_eq!(paths(builder)?, vec![PathBuf::from("src"), PathBuf::from("src/main.rs")]);
+
+		std::fs::remove_dir_all("filter_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn selective_extract_test() -> Result<(), MpkError> {
+		use std::os::unix::fs::MetadataExt;
+
+		create_test_file("selective_test/in/assets/img/a.png", b"A".to_vec())?;
+		create_test_file("selective_test/in/assets/img/b.jpg", b"B".to_vec())?;
+		create_test_file("selective_test/in/assets/sound/c.png", b"C".to_vec())?;
+		std::fs::hard_link("selective_test/in/assets/img/a.png", "selective_test/in/assets/link.png")?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_path(Path::new("selective_test/in"))?;
+		builder.finish(&mut buffer)?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+
+		// Only the pngs, without the leading directory. Hard links still link up when they're moved
+		let pattern = pattern::Pattern::anchored("assets/**/*.png")?;
+		let indices: Vec<usize> = archive.header.entries.iter().enumerate()
+			.filter(|(_, entry)| pattern.matches(&entry.path, false))
+			.map(|(index, _)| index)
+			.collect();
+		let options = ExtractOptions { strip_components: 1, ..ExtractOptions::default() };
+		extract_entries(&mut archive, &indices, Path::new("selective_test/stripped"), &options)?;
+		assert_eq!(std::fs::read("selective_test/stripped/img/a.png")?, b"A");
+		assert_eq!(std::fs::read("selective_test/stripped/sound/c.png")?, b"C");
+		assert!(!Path::new("selective_test/stripped/img/b.jpg").exists());
+		assert_eq!(std::fs::metadata("selective_test/stripped/link.png")?.ino(), std::fs::metadata("selective_test/stripped/img/a.png")?.ino());
+
+		// Everything straight into one directory
+		let options = ExtractOptions { flatten: true, ..ExtractOptions::default() };
+		extract_all_archive(&mut archive, Path::new("selective_test/flat"), &options)?;
+		let mut names: Vec<_> = std::fs::read_dir("selective_test/flat")?.map(|entry| entry.unwrap().file_name()).collect();
+		names.sort();
+		assert_eq!(names, ["a.png", "b.jpg", "c.png", "link.png"]);
+
+		std::fs::remove_dir_all("selective_test")?;
+		Ok(())
+	}
+
+	#[test]
+	fn append_test() -> Result<(), MpkError> {
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_tag("name", "appended");
+		builder.add_bytes(Path::new("a.txt"), b"Original".to_vec());
+		builder.add_directory(Path::new("dir"));
+		builder.add_bytes(Path::new("dir/b.txt"), b"Inside".to_vec());
+		builder.finish(&mut buffer)?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		let created = archive.header.tags[TAG_CREATED].clone();
+		let a_offset = archive.header.entries[0].offset;
+
+		// A duplicate is caught before anything is written
+		let original = archive.file.get_ref().clone();
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("new.txt"), b"New".to_vec());
+		builder.add_bytes(Path::new("a.txt"), b"Clash".to_vec());
+		match archive.append(builder, DuplicatePolicy::Error) {
+			Err(MpkError::DuplicateEntry(path)) => assert_eq!(path, Path::new("a.txt")),
+			other => panic!("Expected a duplicate entry error, got {:?}", other.err())
+		}
+		assert_eq!(archive.file.get_ref(), &original);
+
+		// Directories are merged, and the new data goes on the end without touching the old
+		let mut builder = ArchiveBuilder::new();
+		builder.set_tag("extra", "tag");
+		builder.add_directory(Path::new("dir"));
+		builder.add_bytes(Path::new("dir/c.txt"), b"Added".to_vec());
+		archive.append(builder, DuplicatePolicy::Error)?;
+		assert_eq!(archive.file.get_ref().len(), original.len() + 5);
+		assert_eq!(archive.header.entries.len(), 4);
+		assert_eq!(archive.header.entries[0].offset, a_offset);
+		assert_eq!(archive.header.tags["extra"], "tag");
+		assert_eq!(archive.header.tags["name"], "appended");
+		assert_eq!(archive.header.tags[TAG_CREATED], created);
+		assert_eq!(read(&mut archive, "dir/c.txt")?, b"Added");
+
+		// Replacing drops the old entry, keeping both leaves two with the last one winning
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("a.txt"), b"Replaced".to_vec());
+		archive.append(builder, DuplicatePolicy::Replace)?;
+		assert_eq!(archive.header.entries.iter().filter(|entry| entry.path == Path::new("a.txt")).count(), 1);
+		assert_eq!(read(&mut archive, "a.txt")?, b"Replaced");
+
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("dir/b.txt"), b"Second".to_vec());
+		archive.append(builder, DuplicatePolicy::KeepBoth)?;
+		assert_eq!(archive.header.entries.iter().filter(|entry| entry.path == Path::new("dir/b.txt")).count(), 2);
+		assert_eq!(read(&mut archive, "dir/b.txt")?, b"Second");
+
+		// Outgrowing the space after the header moves the data, and everything can still be read
+		let mut builder = ArchiveBuilder::new();
+		for i in 0..100 {
+			builder.add_bytes(&PathBuf::from(format!("many/{}-{}.txt", "long".repeat(10), i)), i.to_string().into_bytes());
+		}
+		let b_offset = archive.header.entries[1].offset;
+		archive.append(builder, DuplicatePolicy::Error)?;
+		assert_eq!(archive.header.entries[1].path, Path::new("dir/b.txt"));
+		assert!(archive.header.entries[1].offset > b_offset + HEADER_RESERVE);
+		assert!(verify_archive(&mut archive)?.is_empty());
+		assert_eq!(read(&mut archive, "dir/c.txt")?, b"Added");
+		assert_eq!(read(&mut archive, &format!("many/{}-42.txt", "long".repeat(10)))?, b"42");
+
+		let mut reopened = Archive::new(std::io::Cursor::new(archive.into_inner().into_inner()))?;
+		assert_eq!(reopened.header.entries.len(), 105);
+		assert!(verify_archive(&mut reopened)?.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn delete_compact_test() -> Result<(), MpkError> {
+		fn contains(data: &[u8], needle: &[u8]) -> bool {
+			data.windows(needle.len()).any(|window| window == needle)
+		}
+
+		create_test_file("delete_compact_test/keep.txt", b"Keep me".to_vec())?;
+		create_test_file("delete_compact_test/gone.txt", b"GONE GONE GONE".to_vec())?;
+		create_test_file("delete_compact_test/shared.txt", b"Shared data".to_vec())?;
+		std::fs::hard_link("delete_compact_test/shared.txt", "delete_compact_test/shared_link.txt")?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_path(Path::new("delete_compact_test"))?;
+		builder.finish(&mut buffer)?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+
+		// Deleting and replacing only touch the header, so the old data is still there
+		let index = |archive: &Archive<std::io::Cursor<Vec<u8>>>, path: &str| archive.header.entries.iter().position(|entry| entry.path == Path::new(path));
+		// Whichever of the pair was walked first holds the data, so delete that one
+		let (shared, link) = match archive.header.entries[index(&archive, "shared.txt").unwrap()].kind {
+			EntryKind::File => ("shared.txt", "shared_link.txt"),
+			_ => ("shared_link.txt", "shared.txt")
+		};
+		let doomed = vec![index(&archive, "gone.txt").unwrap(), index(&archive, shared).unwrap()];
+		archive.delete(&doomed)?;
+		assert_eq!(archive.header.entries.len(), 2);
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("keep.txt"), b"Replaced".to_vec());
+		archive.replace(builder)?;
+		assert!(contains(archive.file.get_ref(), b"GONE GONE GONE"));
+		assert!(contains(archive.file.get_ref(), b"Keep me"));
+
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("missing.txt"), Vec::new());
+		assert!(archive.replace(builder).is_err());
+
+		// Compacting drops the dead data, but keeps data a hard link still shares with a deleted file
+		let old_len = archive.file.get_ref().len();
+		let len = archive.compact()? as usize;
+		assert!(len < old_len);
+		let mut data = archive.into_inner().into_inner();
+		data.truncate(len);
+		assert!(!contains(&data, b"GONE GONE GONE"));
+		assert!(!contains(&data, b"Keep me"));
+
+		let mut archive = Archive::new(std::io::Cursor::new(data))?;
+		assert!(verify_archive(&mut archive)?.is_empty());
+		for (path, expected) in [("keep.txt", &b"Replaced"[..]), (link, b"Shared data")] {
+			let mut extracted = Vec::new();
+			extract_from_archive(Path::new(path), &mut archive, &mut extracted)?;
+			assert_eq!(extracted, expected);
+		}
+		assert_eq!(archive.compact()? as usize, len);
+
+		std::fs::remove_dir_all("delete_compact_test")?;
+		Ok(())
+	}
+
+	#[test]
+	fn trailing_index_test() -> Result<(), MpkError> {
+		// A Vec can't seek, so this can only work in a single pass
+		let mut data = Vec::new();
+		let mut builder = ArchiveBuilder::new();
+		builder.set_trailing_index(true).set_tag("name", "trailing");
+		builder.add_bytes(Path::new("a.txt"), b"First".to_vec());
+		builder.set_codec(Box::new(codec::Lz4));
+		builder.add_stream(Path::new("piped.txt"), &b"From a pipe, however long it turns out to be"[..]);
+		builder.finish_streaming(&mut data)?;
+		assert_eq!(data[..5], [0x89, b'M', b'P', b'K', ARCHIVE_VERSION]);
+		assert!(data.ends_with(&FOOTER_MAGIC));
+		assert!(matches!(read_header(&mut &data[..]), Err(MpkError::TrailingIndex)));
+
+		let mut archive = Archive::new(std::io::Cursor::new(data.clone()))?;
+		assert!(archive.header.is_trailing());
+		assert_eq!(archive.header.tags["name"], "trailing");
+		assert_eq!(archive.header.entries[1].size, 44);
+		assert!(verify_archive(&mut archive)?.is_empty());
+		assert_eq!(read(&mut archive, "piped.txt")?, b"From a pipe, however long it turns out to be");
+
+		// Without its footer the archive is incomplete
+		let truncated = &data[..data.len() - 1];
+		assert!(matches!(Archive::new(std::io::Cursor::new(truncated)), Err(MpkError::TruncatedHeader)));
+
+		// Glued onto the end of something else it's found from the footer, and can still be edited
+		let mut glued = b"Some program that extracts the archive".to_vec();
+		let stub_len = glued.len();
+		glued.extend_from_slice(&data);
+		let mut file = std::io::Cursor::new(glued);
+		assert_eq!(detect_format(&mut file)?, Format::NotAnArchive);
+		let footer = read_footer(&mut file)?.unwrap();
+		assert_eq!(footer.start, stub_len as u64);
+		file.set_position(footer.start);
+		let mut archive = Archive::new(file)?;
+
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("b.txt"), b"Appended".to_vec());
+		archive.append(builder, DuplicatePolicy::Error)?;
+		assert_eq!(read(&mut archive, "b.txt")?, b"Appended");
+		let index = archive.header.entries.iter().position(|entry| entry.path == Path::new("piped.txt")).unwrap();
+		archive.delete(&[index])?;
+		let len = archive.compact()? as usize;
+		let mut glued = archive.into_inner().into_inner();
+		glued.truncate(len);
+		assert!(glued.starts_with(b"Some program"));
+
+		let mut file = std::io::Cursor::new(glued);
+		let start = read_footer(&mut file)?.unwrap().start;
+		file.set_position(start);
+		let mut archive = Archive::new(file)?;
+		assert!(archive.header.is_trailing());
+		assert_eq!(archive.header.entries.len(), 2);
+		assert!(verify_archive(&mut archive)?.is_empty());
+		assert_eq!(read(&mut archive, "a.txt")?, b"First");
+		assert_eq!(archive.file.get_ref().len(), stub_len + 13 + 5 + 8 + archive.header.size as usize + 20);
+
+		// Streams work in archives with the header at the start too, as it's filled in after the data
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_stream(Path::new("piped.txt"), &b"Streamed"[..]);
+		builder.finish(&mut buffer)?;
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert!(!archive.header.is_trailing());
+		assert_eq!(read(&mut archive, "piped.txt")?, b"Streamed");
+		Ok(())
+	}
+
+	#[test]
+	fn sequential_read_test() -> Result<(), MpkError> {
+		create_test_file("sequential_read_test/in/first.txt", b"First".to_vec())?;
+		create_test_file("sequential_read_test/in/shared.txt", b"Shared data".to_vec())?;
+		std::fs::hard_link("sequential_read_test/in/shared.txt", "sequential_read_test/in/shared_link.txt")?;
+
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_codec(Box::new(codec::Lz4));
+		builder.add_path(Path::new("sequential_read_test/in"))?;
+		builder.finish(&mut buffer)?;
+
+		// Replacing puts the new data after everything else, so it no longer comes in the order of the entries
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("first.txt"), b"Replaced".to_vec());
+		archive.replace(builder)?;
+		let data = archive.into_inner().into_inner();
+
+		// Reading through a slice makes sure nothing can seek backwards
+		let mut archive = Archive::new_sequential(SequentialReader::new(&data[..]))?;
+		assert!(verify_archive(&mut archive)?.is_empty());
+		let mut archive = Archive::new_sequential(SequentialReader::new(&data[..]))?;
+		let all: Vec<usize> = (0..archive.header.entries.len()).collect();
+		extract_entries(&mut archive, &all, Path::new("sequential_read_test/out"), &ExtractOptions::default())?;
+		assert_eq!(std::fs::read("sequential_read_test/out/first.txt")?, b"Replaced");
+		assert_eq!(std::fs::read("sequential_read_test/out/shared.txt")?, b"Shared data");
+		assert_eq!(std::fs::read("sequential_read_test/out/shared_link.txt")?, b"Shared data");
+
+		// Going back over data that's already gone fails rather than reading the wrong thing
+		let mut reader = SequentialReader::new(&data[..]);
+		assert_eq!(reader.seek(SeekFrom::Start(10))?, 10);
+		assert_eq!(reader.stream_position()?, 10);
+		assert!(reader.seek(SeekFrom::Start(5)).is_err());
+		assert!(reader.seek(SeekFrom::End(0)).is_err());
+		assert!(reader.seek(SeekFrom::Start(data.len() as u64 + 1)).is_err());
+
+		// A trailing index can't be reached without reading everything first
+		let mut trailing = Vec::new();
+		let mut builder = ArchiveBuilder::new();
+		builder.set_trailing_index(true);
+		builder.add_bytes(Path::new("a.txt"), b"Data".to_vec());
+		builder.finish_streaming(&mut trailing)?;
+		assert!(matches!(Archive::new_sequential(SequentialReader::new(&trailing[..])), Err(MpkError::TrailingIndex)));
+
+		std::fs::remove_dir_all("sequential_read_test")?;
+		Ok(())
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn stream_skipped_paths_test() -> Result<(), MpkError> {
+		create_test_file("stream_skipped_test/data.txt", b"Kept".to_vec())?;
+		std::os::unix::fs::symlink("nowhere", "stream_skipped_test/broken")?;
+
+		// Skipped paths go to the handler, so nothing but the archive is written where it's going
+		let mut data = Vec::new();
+		let mut warnings = Vec::new();
+		let mut builder = ArchiveBuilder::new();
+		builder.set_warning_handler(|warning| warnings.push(warning));
+		builder.set_dereference(true).set_trailing_index(true).add_path(Path::new("stream_skipped_test"))?;
+		builder.finish_streaming(&mut data)?;
+		std::fs::remove_dir_all("stream_skipped_test")?;
+		assert!(matches!(warnings[..], [MpkError::BrokenLink(ref path)] if path.ends_with("broken")));
+
+		let mut archive = Archive::new(std::io::Cursor::new(data))?;
+		assert_eq!(archive.header.entries.len(), 1);
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("data.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, b"Kept");
+		Ok(())
+	}
+
+	#[test]
+	fn file_change_test() -> Result<(), MpkError> {
+		create_test_file("file_change_test/grows.log", b"Line one\n".to_vec())?;
+		create_test_file("file_change_test/shrinks.txt", b"Long contents".to_vec())?;
+		create_test_file("file_change_test/steady.txt", b"Unchanged".to_vec())?;
+
+		let mut builder = ArchiveBuilder::new();
+		builder.add_path(Path::new("file_change_test"))?;
+		create_test_file("file_change_test/grows.log", b"Line one\nLine two\n".to_vec())?;
+		create_test_file("file_change_test/shrinks.txt", b"Short".to_vec())?;
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		builder.finish(&mut buffer)?;
+
+		// Each entry keeps the size it was added with, so the ones after it still line up
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert!(verify_archive(&mut archive)?.is_empty());
+		for (path, expected, changed) in [("grows.log", &b"Line one\n"[..], true), ("shrinks.txt", b"Short\0\0\0\0\0\0\0\0", true), ("steady.txt", b"Unchanged", false)] {
+			let mut extracted = Vec::new();
+			extract_from_archive(Path::new(path), &mut archive, &mut extracted)?;
+			assert_eq!(extracted, expected);
+			assert_eq!(archive.header.entries.iter().find(|entry| entry.path == Path::new(path)).unwrap().changed, changed);
+		}
+
+		let mut builder = ArchiveBuilder::new();
+		builder.set_on_change(ChangePolicy::Fail);
+		builder.add_path(Path::new("file_change_test"))?;
+		create_test_file("file_change_test/grows.log", b"Line one\nLine two\nLine three\n".to_vec())?;
+		let result = builder.finish(&mut std::io::Cursor::new(Vec::new()));
+		assert!(matches!(result, Err(MpkError::FileChanged(ref path)) if path.ends_with("grows.log")));
+
+		// A file that's gone by the time it's packed is a change too
+		let mut warnings = Vec::new();
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_warning_handler(|warning| warnings.push(warning));
+		builder.add_path(Path::new("file_change_test"))?;
+		std::fs::remove_file("file_change_test/steady.txt")?;
+		builder.finish(&mut buffer)?;
+		assert!(matches!(warnings[..], [MpkError::FileChanged(ref path)] if path.ends_with("steady.txt")));
+		buffer.set_position(0);
+		let mut archive = Archive::new(buffer)?;
+		assert_eq!(read(&mut archive, "steady.txt")?, [0; 9]);
+		assert!(archive.header.entries.iter().find(|entry| entry.path == Path::new("steady.txt")).unwrap().changed);
+		assert_eq!(read(&mut archive, "shrinks.txt")?, b"Short");
+
+		let mut builder = ArchiveBuilder::new();
+		builder.set_on_change(ChangePolicy::Fail);
+		builder.add_path(Path::new("file_change_test"))?;
+		std::fs::remove_file("file_change_test/shrinks.txt")?;
+		let result = builder.finish(&mut std::io::Cursor::new(Vec::new()));
+		assert!(matches!(result, Err(MpkError::FileChanged(ref path)) if path.ends_with("shrinks.txt")));
+
+		std::fs::remove_dir_all("file_change_test")?;
+		Ok(())
+	}
+
+	#[test]
+	fn version_1_archive_test() -> Result<(), MpkError> {
+		// A version 1 archive holding "a.txt" and "b.txt", which only stores entry sizes
+		let mut data = vec![1, 67, 0, 0, 0, 0, 0, 0, 0];
+		data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]); // No tags
+		data.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]); // Two entries
+		data.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend(sized_bit_string("a.txt"));
+		data.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend(sized_bit_string("b.txt"));
+		data.extend_from_slice(b"aaabb");
+
+		let mut archive = Archive::new(std::io::Cursor::new(data))?;
+		assert_eq!(archive.header.entries[1].offset, 70);
+
+		let mut extracted = Vec::new();
+		extract_from_archive(Path::new("b.txt"), &mut archive, &mut extracted)?;
+		assert_eq!(extracted, b"bb");
+
+		Ok(())
+	}
+
+	#[test]
+	fn detect_format_test() -> Result<(), MpkError> {
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.add_bytes(Path::new("a.txt"), b"a".to_vec());
+		builder.finish(&mut buffer)?;
+		buffer.set_position(0);
+		assert_eq!(detect_format(&mut buffer)?, Format::Mpk(ARCHIVE_VERSION));
+		assert_eq!(buffer.position(), 0);
+		assert!(buffer.get_ref().starts_with(&MAGIC));
+
+		let mut version_1 = vec![1, 25, 0, 0, 0, 0, 0, 0, 0];
+		version_1.extend_from_slice(&[0; 16]);
+		assert_eq!(detect_format(&mut std::io::Cursor::new(&version_1))?, Format::Legacy(1));
+		assert!(read_header(&mut &version_1[..]).is_ok());
+
+		// Starting with a plausible version byte isn't enough without a sensible header size
+		for data in [&b"\x01 is a plain text file"[..], b"PK\x03\x04", b"\x89MP", b""].iter() {
+			assert_eq!(detect_format(&mut std::io::Cursor::new(data))?, Format::NotAnArchive);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn bad_header_test() {
+		// Only the version byte, the header size is missing
+		let path = PathBuf::from("bad_header_test.mpk");
+		create_test_file("bad_header_test.mpk", vec![1]).unwrap();
+		let result = read_header(&mut File::open(&path).unwrap());
+		assert!(matches!(result, Err(MpkError::TruncatedHeader)));
+
+		// A version from the future
+		create_test_file("bad_header_test.mpk", vec![0x89, b'M', b'P', b'K', 200, 13, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+		let result = read_header(&mut File::open(&path).unwrap());
+		assert!(matches!(result, Err(MpkError::UnsupportedVersion(200))));
+
+		// Something that was never an archive
+		create_test_file("bad_header_test.mpk", b"Just some text".to_vec()).unwrap();
+		let result = read_header(&mut File::open(&path).unwrap());
+		assert!(matches!(result, Err(MpkError::NotAnArchive)));
+
+		// A tag count promising more tags than there are bytes
+		create_test_file("bad_header_test.mpk", vec![1, 17, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+		let result = read_header(&mut File::open(&path).unwrap());
+		assert!(matches!(result, Err(MpkError::TruncatedHeader)));
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	/// Sets the header checksum of a version 8+ archive to match its (possibly mangled) header
+	fn fix_header_checksum(data: &mut [u8]) {
+		let size = match data.get(5..13) {
+			Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap()) as usize,
+			None => return
+		};
+		if size >= 17 && size <= data.len() {
+			let checksum = crc32c::crc32c(&data[..size - 4]);
+			data[size - 4..size].copy_from_slice(&checksum.to_le_bytes());
+		}
+	}
+
+	#[test]
+	fn malicious_header_test() {
+		let huge = u64::MAX.to_le_bytes();
+
+		// Claims an enormous header, which shouldn't be allocated
+		let mut data = MAGIC.to_vec();
+		data.push(ARCHIVE_VERSION);
+		data.extend_from_slice(&huge);
+		assert!(matches!(read_header(&mut &data[..]), Err(MpkError::HeaderTooLarge(_))));
+
+		// Claims the biggest header allowed, but has nothing behind it
+		let mut data = MAGIC.to_vec();
+		data.push(ARCHIVE_VERSION);
+		data.extend_from_slice(&MAX_HEADER_SIZE.to_le_bytes());
+		assert!(matches!(read_header(&mut &data[..]), Err(MpkError::TruncatedHeader)));
+
+		// A header size too small to even hold itself, or its checksum
+		assert!(matches!(read_header(&mut &[1, 3, 0, 0, 0, 0, 0, 0, 0][..]), Err(MpkError::TruncatedHeader)));
+		assert!(matches!(read_header(&mut &[7, 9, 0, 0, 0, 0, 0, 0, 0][..]), Err(MpkError::TruncatedHeader)));
+		assert!(matches!(read_header(&mut &[0x89, b'M', b'P', b'K', 8, 13, 0, 0, 0, 0, 0, 0, 0][..]), Err(MpkError::TruncatedHeader)));
+
+		// Version 1 headers have no checksum, so their counts and lengths go straight to the parser
+		let version_1 = |body: &[u8]| {
+			let mut data = vec![1];
+			data.extend_from_slice(&(9 + body.len() as u64).to_le_bytes());
+			data.extend_from_slice(body);
+			read_header(&mut &data[..])
+		};
+		assert!(matches!(version_1(&huge), Err(MpkError::TooManyTags(_))));
+
+		let mut body = vec![0; 8]; // No tags
+		body.extend_from_slice(&huge);
+		assert!(matches!(version_1(&body), Err(MpkError::TooManyEntries(_))));
+
+		let mut body = vec![0; 8];
+		body.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]); // One entry
+		body.extend_from_slice(&[0; 8]); // Of size 0
+		let mut long_path = body.clone();
+		long_path.extend_from_slice(&huge);
+		assert!(matches!(version_1(&long_path), Err(MpkError::PathTooLong(_))));
+
+		let mut cut_path = body.clone();
+		cut_path.extend_from_slice(&[100, 0, 0, 0, 0, 0, 0, 0]);
+		cut_path.extend_from_slice(b"short");
+		assert!(matches!(version_1(&cut_path), Err(MpkError::TruncatedHeader)));
+
+		let mut bad_utf8 = body.clone();
+		bad_utf8.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 0, 0xc3, 0x28]);
+		assert!(matches!(version_1(&bad_utf8), Err(MpkError::InvalidUtf8Path(_))));
+
+		// A version 2 entry whose data would wrap around the end of the file
+		let mut data = vec![2];
+		data.extend_from_slice(&58u64.to_le_bytes());
+		data.extend_from_slice(&[0; 8]);
+		data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+		data.extend_from_slice(&4u64.to_le_bytes()); // Size
+		data.extend_from_slice(&(u64::MAX - 1).to_le_bytes()); // Offset
+		data.extend_from_slice(&4u64.to_le_bytes()); // Stored size
+		data.extend(sized_bit_string("a"));
+		data.extend_from_slice(b"data");
+		assert!(matches!(Archive::new(std::io::Cursor::new(data)), Err(MpkError::EntryOutOfBounds(_))));
+	}
+
+	#[test]
+	fn header_mutation_test() -> Result<(), MpkError> {
+		// An archive using as much of the format as possible
+		let mut buffer = std::io::Cursor::new(Vec::new());
+		let mut builder = ArchiveBuilder::new();
+		builder.set_tag("name", "mutated");
+		builder.add_directory(Path::new("folder"));
+		builder.add_bytes(Path::new("folder/stored.txt"), b"Stored as is".to_vec());
+		builder.set_metadata(Metadata { mode: 0o640, user: Some(String::from("someone")), ..Metadata::default() });
+		builder.set_codec(Box::new(codec::Lz4));
+		builder.add_bytes(Path::new("compressed.txt"), b"Compressed compressed compressed".to_vec());
+		builder.add_symlink(Path::new("link"), Path::new("compressed.txt"));
+		builder.finish(&mut buffer)?;
+		let data = buffer.into_inner();
+		let header_size = Archive::new(std::io::Cursor::new(&data))?.header.size as usize;
+
+		// Whatever the parser is given it should return an error rather than panic or hang
+		let check = |data: &[u8]| {
+			if let Ok(mut archive) = Archive::new(std::io::Cursor::new(data)) {
+				let _ = verify_archive(&mut archive);
+				for mut entry in archive.entries() {
+					let _ = entry.read_to_end(&mut Vec::new());
+				}
+			}
+		};
+
+		for len in 0..data.len() {
+			check(&data[..len]);
+		}
+		for index in 0..data.len() {
+			for flip in &[0x01, 0x80, 0xff] {
+				let mut mutated = data.clone();
+				mutated[index] ^= flip;
+				// Mangled headers would mostly be caught by their checksum, so fix it up to reach the parser
+				if index < header_size - 4 {
+					fix_header_checksum(&mut mutated);
+				}
+				check(&mutated);
+			}
+		}
 
 		Ok(())
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,231 @@
+//! Codecs are [`Transform`]s that encode an entry's data as it's written into an archive, usually to compress it.
+//! The id of the codec used for each entry is stored in the header, so the right decoder can be picked
+//! without the user having to know how the archive was packed.
+
+use std::io;
+use std::io::prelude::*;
+
+use lz4_flex::block;
+
+use archiver::transform::{Encoder, Transform};
+
+/// A way of encoding entry data that's recorded in the header, such as a compression algorithm
+pub trait Codec: Transform {
+	/// The id stored in the header of entries encoded with this codec. Ids below 128 are reserved for built in codecs
+	fn id(&self) -> u8;
+
+	/// A short name for the codec, used to pick it on the command line
+	fn name(&self) -> &'static str;
+}
+
+/// Returns every codec that comes with the archiver
+pub fn builtin_codecs() -> Vec<Box<dyn Codec>> {
+	vec![Box::new(Store), Box::new(Lz4)]
+}
+
+/// Finds a built in codec by its name, ignoring case
+pub fn codec_by_name(name: &str) -> Option<Box<dyn Codec>> {
+	builtin_codecs().into_iter().find(|codec| codec.name().eq_ignore_ascii_case(name))
+}
+
+// Store ********************************************************
+
+/// Stores data exactly as it was given
+pub struct Store;
+
+impl Codec for Store {
+	fn id(&self) -> u8 { 0 }
+	fn name(&self) -> &'static str { "store" }
+}
+
+impl Transform for Store {
+	fn encoder<'a>(&self, output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a> {
+		output
+	}
+
+	fn decoder<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+		input
+	}
+}
+
+// LZ4 ********************************************************
+
+/// The most data compressed as a single block, decoders refuse blocks claiming to be bigger than this
+const LZ4_BLOCK_SIZE: usize = 64 * 1024;
+/// Set on a block's stored length when the block is kept uncompressed, because compressing made it bigger
+const LZ4_UNCOMPRESSED_FLAG: u32 = 1 << 31;
+
+/// Compresses data with LZ4, which is fast but doesn't compress as well as some other algorithms
+///
+/// The data is split into blocks of up to [`LZ4_BLOCK_SIZE`] bytes, each written as its stored length (u32 little endian,
+/// with [`LZ4_UNCOMPRESSED_FLAG`] set if the block isn't compressed), its original length (u32 little endian), then the block itself.
+/// A stored length of 0 marks the end of the data, so a decoder knows where to stop without being told the stored size
+pub struct Lz4;
+
+struct Lz4Encoder<'a> {
+	output: Box<dyn Encoder + 'a>,
+	buffer: Vec<u8> // Data waiting for a full block before being compressed
+}
+
+struct Lz4Decoder<'a> {
+	input: Box<dyn Read + 'a>,
+	block: Vec<u8>, // The most recently decoded block
+	position: usize, // How much of `block` has been read
+	finished: bool
+}
+
+impl Codec for Lz4 {
+	fn id(&self) -> u8 { 1 }
+	fn name(&self) -> &'static str { "lz4" }
+}
+
+impl Transform for Lz4 {
+	fn encoder<'a>(&self, output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a> {
+		Box::new(Lz4Encoder { output, buffer: Vec::with_capacity(LZ4_BLOCK_SIZE) })
+	}
+
+	fn decoder<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+		Box::new(Lz4Decoder { input, block: Vec::new(), position: 0, finished: false })
+	}
+}
+
+impl<'a> Lz4Encoder<'a> {
+	fn write_block(&mut self) -> io::Result<()> {
+		if self.buffer.is_empty() {
+			return Ok(());
+		}
+
+		let compressed = block::compress(&self.buffer);
+		let (stored_len, data) = if compressed.len() < self.buffer.len() {
+			(compressed.len() as u32, &compressed[..])
+		} else {
+			(self.buffer.len() as u32 | LZ4_UNCOMPRESSED_FLAG, &self.buffer[..])
+		};
+
+		self.output.write_all(&stored_len.to_le_bytes())?;
+		self.output.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+		self.output.write_all(data)?;
+		self.buffer.clear();
+		Ok(())
+	}
+}
+
+impl<'a> Write for Lz4Encoder<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let len = std::cmp::min(buf.len(), LZ4_BLOCK_SIZE - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..len]);
+		if self.buffer.len() == LZ4_BLOCK_SIZE {
+			self.write_block()?;
+		}
+		Ok(len)
+	}
+
+	// Blocks are only written once they're full, so there's nothing to do but flush the output
+	fn flush(&mut self) -> io::Result<()> { self.output.flush() }
+}
+
+impl<'a> Encoder for Lz4Encoder<'a> {
+	fn finish(mut self: Box<Self>) -> io::Result<()> {
+		self.write_block()?;
+		self.output.write_all(&0u32.to_le_bytes())?;
+		self.output.finish()
+	}
+}
+
+impl<'a> Lz4Decoder<'a> {
+	/// Reads and decodes the next block, returning false at the end of the data
+	fn next_block(&mut self) -> io::Result<bool> {
+		let mut len_buf = [0u8; 4];
+		self.input.read_exact(&mut len_buf)?;
+		let stored_len = u32::from_le_bytes(len_buf);
+		if stored_len == 0 {
+			return Ok(false);
+		}
+		self.input.read_exact(&mut len_buf)?;
+		let original_len = u32::from_le_bytes(len_buf) as usize;
+
+		let uncompressed = stored_len & LZ4_UNCOMPRESSED_FLAG != 0;
+		let stored_len = (stored_len & !LZ4_UNCOMPRESSED_FLAG) as usize;
+		if original_len > LZ4_BLOCK_SIZE || stored_len > block::get_maximum_output_size(LZ4_BLOCK_SIZE) {
+			return Err(invalid_data("LZ4 block is larger than the maximum block size"));
+		}
+
+		let mut stored = vec![0u8; stored_len];
+		self.input.read_exact(&mut stored)?;
+
+		if uncompressed {
+			if stored_len != original_len {
+				return Err(invalid_data("Uncompressed LZ4 block has the wrong length"));
+			}
+			self.block = stored;
+		} else {
+			self.block.resize(original_len, 0);
+			let len = block::decompress_into(&stored, &mut self.block).map_err(|why| invalid_data(&why.to_string()))?;
+			if len != original_len {
+				return Err(invalid_data("LZ4 block decompressed to the wrong length"));
+			}
+		}
+
+		self.position = 0;
+		Ok(true)
+	}
+}
+
+impl<'a> Read for Lz4Decoder<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.position == self.block.len() {
+			if self.finished || !self.next_block()? {
+				self.finished = true;
+				return Ok(0);
+			}
+		}
+
+		let len = std::cmp::min(buf.len(), self.block.len() - self.position);
+		buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+		self.position += len;
+		Ok(len)
+	}
+}
+
+fn invalid_data(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use archiver::transform::PlainEncoder;
+
+	#[test]
+	fn codec_round_trip_test() -> io::Result<()> {
+		// Repetitive enough to compress, and long enough to need several blocks
+		let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+		for codec in builtin_codecs() {
+			let mut encoded = Vec::new();
+			let mut encoder = codec.encoder(Box::new(PlainEncoder(&mut encoded)));
+			encoder.write_all(&data)?;
+			encoder.finish()?;
+
+			// Trailing bytes after the encoded data shouldn't be read by the decoder
+			let encoded_len = encoded.len();
+			encoded.extend_from_slice(b"trailing");
+			if codec.name() == "lz4" {
+				assert!(encoded_len < data.len());
+			}
+
+			let mut input = &encoded[..];
+			let mut decoded = Vec::new();
+			if codec.name() == "store" {
+				codec.decoder(Box::new((&mut input).take(encoded_len as u64))).read_to_end(&mut decoded)?;
+			} else {
+				codec.decoder(Box::new(&mut input)).read_to_end(&mut decoded)?;
+			}
+			assert_eq!(decoded, data, "{} didn't round trip", codec.name());
+			assert_eq!(input, b"trailing");
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,259 @@
+//! File metadata that can be stored alongside an entry: permissions, timestamps and ownership.
+//! Metadata is captured from the filesystem when packing, and restored onto extracted files according to [`ExtractOptions`].
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use archiver::ExtractOptions;
+use archiver::sandbox::Sandbox;
+
+/// A point in time, counted from the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timestamp {
+	pub secs: i64, // Negative for times before 1970
+	pub nanos: u32 // Always below 1_000_000_000
+}
+
+/// Metadata of a file, as it was when it was packed
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Metadata {
+	pub mode: u32, // Unix permission bits, including setuid, setgid and sticky
+	pub modified: Timestamp,
+	pub accessed: Timestamp,
+	pub uid: u32,
+	pub gid: u32,
+	pub user: Option<String>, // Name of the owner, preferred over `uid` when restoring if it exists on the system
+	pub group: Option<String> // Name of the group, preferred over `gid` when restoring if it exists on the system
+}
+
+impl Metadata {
+	/// Captures the metadata of a file from its filesystem metadata
+	#[cfg(unix)]
+	pub fn from_fs(metadata: &fs::Metadata) -> Metadata {
+		use std::os::unix::fs::MetadataExt;
+
+		Metadata {
+			mode: metadata.mode() & 0o7777,
+			modified: Timestamp { secs: metadata.mtime(), nanos: metadata.mtime_nsec() as u32 },
+			accessed: Timestamp { secs: metadata.atime(), nanos: metadata.atime_nsec() as u32 },
+			uid: metadata.uid(),
+			gid: metadata.gid(),
+			user: unix::user_name(metadata.uid()),
+			group: unix::group_name(metadata.gid())
+		}
+	}
+
+	/// Captures the metadata of a file from its filesystem metadata.
+	/// Only the read only flag and timestamps exist outside of Unix, so ownership is left empty
+	#[cfg(not(unix))]
+	pub fn from_fs(metadata: &fs::Metadata) -> Metadata {
+		Metadata {
+			mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 },
+			modified: metadata.modified().map(Timestamp::from).unwrap_or_default(),
+			accessed: metadata.accessed().map(Timestamp::from).unwrap_or_default(),
+			..Metadata::default()
+		}
+	}
+}
+
+impl From<std::time::SystemTime> for Timestamp {
+	fn from(time: std::time::SystemTime) -> Timestamp {
+		match time.duration_since(std::time::UNIX_EPOCH) {
+			Ok(since) => Timestamp { secs: since.as_secs() as i64, nanos: since.subsec_nanos() },
+			Err(why) => {
+				// Before the epoch, so count backwards while keeping the nanoseconds positive
+				let before = why.duration();
+				match before.subsec_nanos() {
+					0 => Timestamp { secs: -(before.as_secs() as i64), nanos: 0 },
+					nanos => Timestamp { secs: -(before.as_secs() as i64) - 1, nanos: 1_000_000_000 - nanos }
+				}
+			}
+		}
+	}
+}
+
+impl fmt::Display for Timestamp {
+	/// Formats the time as an RFC 3339 UTC timestamp, like 2024-02-29T13:45:00Z. Nanoseconds are left off
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let days = self.secs.div_euclid(86400);
+		let secs_of_day = self.secs.rem_euclid(86400);
+
+		// Turn days since the epoch into a date in the proleptic Gregorian calendar,
+		// counting in 400 year eras that start on the 1st of March so leap days come last
+		let days = days + 719_468;
+		let era = days.div_euclid(146_097);
+		let day_of_era = days.rem_euclid(146_097);
+		let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+		let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+		let month_index = (5 * day_of_year + 2) / 153; // 0 is March
+		let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+		let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+		let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+		write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+	}
+}
+
+/// Applies `metadata` to the file at `path`, skipping whatever `options` says not to restore.
+/// If `path` is a symlink the link itself is changed rather than what it points to
+pub fn restore(path: &Path, metadata: &Metadata, options: &ExtractOptions) -> io::Result<()> {
+	let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+	let parent = match path.parent() {
+		Some(parent) if !parent.as_os_str().is_empty() => parent,
+		_ => Path::new(".")
+	};
+	Sandbox::open(parent)?.restore_metadata(Path::new(name), metadata, options)
+}
+
+/// Works out who should own a restored file. Names are preferred over ids when they exist on this system,
+/// as ids often differ between systems
+#[cfg(unix)]
+pub fn owner_ids(metadata: &Metadata) -> (u32, u32) {
+	let uid = metadata.user.as_ref().and_then(|name| unix::user_id(name)).unwrap_or(metadata.uid);
+	let gid = metadata.group.as_ref().and_then(|name| unix::group_id(name)).unwrap_or(metadata.gid);
+	(uid, gid)
+}
+
+/// Identifies the file behind `metadata` by its device and inode, if it has more than one hard link
+#[cfg(unix)]
+pub fn hardlink_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+	use std::os::unix::fs::MetadataExt;
+
+	if metadata.is_file() && metadata.nlink() > 1 {
+		Some((metadata.dev(), metadata.ino()))
+	} else {
+		None
+	}
+}
+
+/// Identifies the file behind `metadata` by its device and inode, if it has more than one hard link.
+/// That isn't available outside of Unix, so hard links are stored as separate files
+#[cfg(not(unix))]
+pub fn hardlink_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+	None
+}
+
+/// The name of the machine the archiver is running on, if it can be found
+#[cfg(unix)]
+pub fn host_name() -> Option<String> {
+	let mut buffer = [0u8; 256];
+	if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+		return None;
+	}
+	let len = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+	String::from_utf8(buffer[..len].to_vec()).ok().filter(|name| !name.is_empty())
+}
+
+/// The name of the machine the archiver is running on, if it can be found
+#[cfg(not(unix))]
+pub fn host_name() -> Option<String> {
+	std::env::var("COMPUTERNAME").ok().filter(|name| !name.is_empty())
+}
+
+/// Whether the archiver is running with enough privileges to give files away to other users
+#[cfg(unix)]
+pub fn is_privileged() -> bool {
+	unsafe { libc::geteuid() == 0 }
+}
+
+/// Whether the archiver is running with enough privileges to give files away to other users
+#[cfg(not(unix))]
+pub fn is_privileged() -> bool {
+	false
+}
+
+#[cfg(unix)]
+mod unix {
+	use std::ffi::{CStr, CString};
+
+	/// Calls one of the reentrant passwd/group lookup functions, growing the string buffer until it fits.
+	/// `lookup` gets the buffer and returns the name or id it found, or an errno
+	fn with_buffer<T, F: FnMut(&mut Vec<libc::c_char>) -> Result<Option<T>, i32>>(mut lookup: F) -> Option<T> {
+		let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+		loop {
+			match lookup(&mut buffer) {
+				Err(libc::ERANGE) if buffer.len() < 1024 * 1024 => {
+					let len = buffer.len() * 2;
+					buffer.resize(len, 0);
+				},
+				Err(_) => return None,
+				Ok(found) => return found
+			}
+		}
+	}
+
+	pub fn user_name(uid: u32) -> Option<String> {
+		with_buffer(|buffer| unsafe {
+			let mut passwd: libc::passwd = std::mem::zeroed();
+			let mut result = std::ptr::null_mut();
+			match libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+				0 if result.is_null() => Ok(None),
+				0 => Ok(CStr::from_ptr(passwd.pw_name).to_str().ok().map(String::from)),
+				errno => Err(errno)
+			}
+		})
+	}
+
+	pub fn group_name(gid: u32) -> Option<String> {
+		with_buffer(|buffer| unsafe {
+			let mut group: libc::group = std::mem::zeroed();
+			let mut result = std::ptr::null_mut();
+			match libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+				0 if result.is_null() => Ok(None),
+				0 => Ok(CStr::from_ptr(group.gr_name).to_str().ok().map(String::from)),
+				errno => Err(errno)
+			}
+		})
+	}
+
+	pub fn user_id(name: &str) -> Option<u32> {
+		let name = CString::new(name).ok()?;
+		with_buffer(|buffer| unsafe {
+			let mut passwd: libc::passwd = std::mem::zeroed();
+			let mut result = std::ptr::null_mut();
+			match libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+				0 if result.is_null() => Ok(None),
+				0 => Ok(Some(passwd.pw_uid)),
+				errno => Err(errno)
+			}
+		})
+	}
+
+	pub fn group_id(name: &str) -> Option<u32> {
+		let name = CString::new(name).ok()?;
+		with_buffer(|buffer| unsafe {
+			let mut group: libc::group = std::mem::zeroed();
+			let mut result = std::ptr::null_mut();
+			match libc::getgrnam_r(name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) {
+				0 if result.is_null() => Ok(None),
+				0 => Ok(Some(group.gr_gid)),
+				errno => Err(errno)
+			}
+		})
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn timestamp_before_epoch_test() {
+		let time = std::time::UNIX_EPOCH - std::time::Duration::new(5, 250_000_000);
+		assert_eq!(Timestamp::from(time), Timestamp { secs: -6, nanos: 750_000_000 });
+
+		let time = std::time::UNIX_EPOCH + std::time::Duration::new(5, 250_000_000);
+		assert_eq!(Timestamp::from(time), Timestamp { secs: 5, nanos: 250_000_000 });
+	}
+
+	#[test]
+	fn timestamp_display_test() {
+		assert_eq!(Timestamp { secs: 0, nanos: 0 }.to_string(), "1970-01-01T00:00:00Z");
+		assert_eq!(Timestamp { secs: 951_827_696, nanos: 5 }.to_string(), "2000-02-29T12:34:56Z");
+		assert_eq!(Timestamp { secs: 1_709_164_800, nanos: 0 }.to_string(), "2024-02-29T00:00:00Z");
+		assert_eq!(Timestamp { secs: -1, nanos: 0 }.to_string(), "1969-12-31T23:59:59Z");
+	}
+}
@@ -0,0 +1,355 @@
+//! Glob patterns for picking entries by their path, used to choose what gets listed, packed and extracted.
+//!
+//! `*` matches any run of characters inside one path component, `?` any single character, and `[abc]`, `[a-z]` or
+//! `[!abc]` one character that is (or isn't) in the set. `**` matches across components, so `docs/**/*.md` finds
+//! markdown files at any depth under `docs`. `\` takes the next character literally.
+//!
+//! Like a .gitignore, a pattern with no `/` in it can match at any depth, so `*.txt` matches both `notes.txt` and
+//! `docs/notes.txt`, while `docs/*.txt` only matches directly inside `docs`. A trailing `/` only matches directories.
+//! Everything inside a matched directory is matched too.
+
+use std::path::{Component, Path, PathBuf};
+
+use archiver::MpkError;
+
+/// A compiled glob pattern, see the [module documentation](self) for the syntax
+#[derive(Debug, Clone)]
+pub struct Pattern {
+	source: String, // The pattern as it was given
+	tokens: Vec<Token>,
+	anchored: bool, // Whether the pattern is matched from the root, rather than against any component
+	directories_only: bool // Set by a trailing slash
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Literal(char),
+	AnyChar, // ?
+	AnyRun, // *, never crosses a slash
+	AnyPath, // ** on its own, which can cross slashes
+	AnyDirectories, // **/, which matches zero or more whole directories
+	Class { negated: bool, ranges: Vec<(char, char)> }
+}
+
+impl Pattern {
+	pub fn new(source: &str) -> Result<Pattern, MpkError> {
+		let mut pattern = source;
+		let directories_only = pattern.len() > 1 && pattern.ends_with('/');
+		if directories_only {
+			pattern = &pattern[..pattern.len() - 1];
+		}
+		let anchored = pattern.contains('/');
+		let pattern = pattern.trim_start_matches('/');
+		if pattern.is_empty() {
+			return Err(MpkError::InvalidPattern(source.to_string()));
+		}
+
+		let chars: Vec<char> = pattern.chars().collect();
+		let mut tokens = Vec::new();
+		let mut i = 0;
+		while i < chars.len() {
+			match chars[i] {
+				'*' if chars.get(i + 1) == Some(&'*') => {
+					// Only a whole component of ** is special, anything like a** is just two stars
+					let starts_component = i == 0 || chars[i - 1] == '/';
+					if starts_component && chars.get(i + 2) == Some(&'/') {
+						tokens.push(Token::AnyDirectories);
+						i += 3;
+					} else if starts_component && i + 2 == chars.len() {
+						tokens.push(Token::AnyPath);
+						i += 2;
+					} else {
+						tokens.push(Token::AnyRun);
+						i += 2;
+					}
+					continue;
+				},
+				'*' => tokens.push(Token::AnyRun),
+				'?' => tokens.push(Token::AnyChar),
+				'\\' => {
+					i += 1;
+					let c = *chars.get(i).ok_or_else(|| MpkError::InvalidPattern(source.to_string()))?;
+					tokens.push(Token::Literal(c));
+				},
+				'[' => {
+					let (token, end) = parse_class(&chars, i).ok_or_else(|| MpkError::InvalidPattern(source.to_string()))?;
+					tokens.push(token);
+					i = end;
+				},
+				c => tokens.push(Token::Literal(c))
+			}
+			i += 1;
+		}
+
+		Ok(Pattern { source: source.to_string(), tokens, anchored, directories_only })
+	}
+
+	/// Compiles a pattern that's always matched from the root, even without a `/` in it.
+	/// Useful for patterns that are meant as paths, so `notes.txt` doesn't match `docs/notes.txt`
+	pub fn anchored(source: &str) -> Result<Pattern, MpkError> {
+		let mut pattern = Pattern::new(&format!("/{}", source.trim_start_matches('/')))
+			.map_err(|_| MpkError::InvalidPattern(source.to_string()))?;
+		pattern.source = source.to_string();
+		Ok(pattern)
+	}
+
+	/// The pattern as it was given
+	pub fn as_str(&self) -> &str {
+		&self.source
+	}
+
+	/// Whether `path` (relative to the root of the archive or the tree being packed) matches the pattern, or is inside
+	/// a directory that does. `is_dir` says whether `path` itself is a directory
+	pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+		let components: Vec<String> = path.components().filter_map(|component| match component {
+			Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+			_ => None
+		}).collect();
+
+		for end in 1..=components.len() {
+			// Only directories can have more components after them
+			if self.directories_only && end == components.len() && !is_dir {
+				break;
+			}
+			let candidate: Vec<char> = if self.anchored {
+				components[..end].join("/").chars().collect()
+			} else {
+				components[end - 1].chars().collect()
+			};
+			if match_tokens(&self.tokens, &candidate) {
+				return true;
+			}
+		}
+		false
+	}
+}
+
+/// Parses the character class starting at the `[` at `start`, returning it and the index of its closing `]`
+fn parse_class(chars: &[char], start: usize) -> Option<(Token, usize)> {
+	let mut i = start + 1;
+	let negated = matches!(chars.get(i), Some('!') | Some('^'));
+	if negated {
+		i += 1;
+	}
+
+	let mut ranges = Vec::new();
+	let mut first = true;
+	loop {
+		let c = *chars.get(i)?;
+		if c == ']' && !first {
+			return Some((Token::Class { negated, ranges }, i));
+		}
+		first = false;
+		if c == '/' {
+			return None;
+		}
+
+		let low = if c == '\\' { i += 1; *chars.get(i)? } else { c };
+		if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&high| high != ']') {
+			let high = chars[i + 2];
+			ranges.push((low, high));
+			i += 3;
+		} else {
+			ranges.push((low, low));
+			i += 1;
+		}
+	}
+}
+
+/// Matches `text` against all of `tokens`, backtracking over the wildcards
+fn match_tokens(tokens: &[Token], text: &[char]) -> bool {
+	let token = match tokens.first() {
+		None => return text.is_empty(),
+		Some(token) => token
+	};
+	let rest = &tokens[1..];
+
+	match token {
+		Token::Literal(c) => text.first() == Some(c) && match_tokens(rest, &text[1..]),
+		Token::AnyChar => text.first().is_some_and(|&c| c != '/') && match_tokens(rest, &text[1..]),
+		Token::Class { negated, ranges } => match text.first() {
+			Some(&c) if c != '/' && ranges.iter().any(|&(low, high)| low <= c && c <= high) != *negated => match_tokens(rest, &text[1..]),
+			_ => false
+		},
+		Token::AnyRun => {
+			let run = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+			(0..=run).any(|len| match_tokens(rest, &text[len..]))
+		},
+		Token::AnyPath => (0..=text.len()).any(|len| match_tokens(rest, &text[len..])),
+		Token::AnyDirectories => (0..=text.len())
+			.filter(|&len| len == 0 || text[len - 1] == '/')
+			.any(|len| match_tokens(rest, &text[len..]))
+	}
+}
+
+/// Picks paths by a list of patterns to include and a list to exclude. With no include patterns everything is
+/// included, and exclude patterns always win
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+	pub include: Vec<Pattern>,
+	pub exclude: Vec<Pattern>
+}
+
+impl PathFilter {
+	pub fn new() -> PathFilter {
+		PathFilter::default()
+	}
+
+	/// Whether there's nothing to filter by
+	pub fn is_empty(&self) -> bool {
+		self.include.is_empty() && self.exclude.is_empty()
+	}
+
+	/// Whether `path` gets through the filter, see [`Pattern::matches`]
+	pub fn allows(&self, path: &Path, is_dir: bool) -> bool {
+		self.includes(path, is_dir) && !self.excludes(path, is_dir)
+	}
+
+	/// Whether `path` matches one of the include patterns, or there aren't any
+	pub fn includes(&self, path: &Path, is_dir: bool) -> bool {
+		self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(path, is_dir))
+	}
+
+	/// Whether `path` matches one of the exclude patterns
+	pub fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+		self.exclude.iter().any(|pattern| pattern.matches(path, is_dir))
+	}
+
+	/// Adds an exclude pattern for each line of the file at `path`. Blank lines and lines starting with `#` are skipped
+	pub fn add_exclude_file(&mut self, path: &Path) -> Result<(), MpkError> {
+		let contents = std::fs::read_to_string(path)?;
+		for line in contents.lines() {
+			let line = line.trim_end();
+			if !line.is_empty() && !line.starts_with('#') {
+				self.exclude.push(Pattern::new(line)?);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The files read for [`IgnoreRules`] by default
+pub const DEFAULT_IGNORE_FILES: [&str; 2] = [".gitignore", ".mpkignore"];
+
+/// The rules of a .gitignore style file. Each line is a pattern relative to the directory the file is in,
+/// a line starting with `!` brings back paths an earlier line ignored, and the last line to match wins.
+/// Blank lines and lines starting with `#` are skipped, as are patterns that can't be parsed
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+	base: PathBuf, // The directory the rules apply inside of
+	rules: Vec<(Pattern, bool)> // Each pattern, and whether it's negated
+}
+
+impl IgnoreRules {
+	/// Parses the contents of an ignore file that was found in `base`
+	pub fn parse(base: &Path, contents: &str) -> IgnoreRules {
+		let rules = contents.lines().filter_map(|line| {
+			let line = line.trim_end();
+			if line.is_empty() || line.starts_with('#') {
+				return None;
+			}
+			let (line, negated) = match line.strip_prefix('!') {
+				Some(rest) => (rest, true),
+				None => (line, false)
+			};
+			Pattern::new(line).ok().map(|pattern| (pattern, negated))
+		}).collect();
+
+		IgnoreRules { base: base.to_path_buf(), rules }
+	}
+
+	/// Whether these rules ignore `path`, or bring it back if it was ignored. `None` if no rule says anything about it
+	pub fn check(&self, path: &Path, is_dir: bool) -> Option<bool> {
+		let path = path.strip_prefix(&self.base).ok()?;
+		self.rules.iter().rev()
+			.find(|(pattern, _)| pattern.matches(path, is_dir))
+			.map(|(_, negated)| !negated)
+	}
+}
+
+/// Whether `path` is ignored by a stack of rules, ordered from the outermost directory in. Rules further in win
+pub fn is_ignored(rules: &[IgnoreRules], path: &Path, is_dir: bool) -> bool {
+	rules.iter().rev().find_map(|rules| rules.check(path, is_dir)).unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matches(pattern: &str, path: &str) -> bool {
+		Pattern::new(pattern).unwrap().matches(Path::new(path), false)
+	}
+
+	#[test]
+	fn pattern_test() {
+		assert!(matches("*.txt", "notes.txt"));
+		assert!(matches("*.txt", "docs/notes.txt"));
+		assert!(!matches("*.txt", "notes.txt.bak"));
+		assert!(matches("docs/*.txt", "docs/notes.txt"));
+		assert!(!matches("docs/*.txt", "other/docs/notes.txt"));
+		assert!(!matches("docs/*.txt", "docs/deeper/notes.txt"));
+		assert!(matches("/notes.txt", "notes.txt"));
+		assert!(!matches("/notes.txt", "docs/notes.txt"));
+		assert!(!Pattern::anchored("notes.txt").unwrap().matches(Path::new("docs/notes.txt"), false));
+		assert!(Pattern::anchored("docs").unwrap().matches(Path::new("docs/notes.txt"), false));
+
+		// Directories match everything inside them
+		assert!(matches("docs", "docs/deeper/notes.txt"));
+		assert!(matches("deeper", "docs/deeper/notes.txt"));
+		assert!(!matches("doc", "docs/notes.txt"));
+
+		assert!(matches("docs/**/*.md", "docs/a/b/c.md"));
+		assert!(matches("docs/**/*.md", "docs/c.md"));
+		assert!(matches("**/c.md", "c.md"));
+		assert!(matches("docs/**", "docs/a/b/c.md"));
+
+		assert!(matches("file?.[ch]", "file1.c"));
+		assert!(!matches("file?.[ch]", "file12.c"));
+		assert!(matches("[!a-c]*", "dog"));
+		assert!(!matches("[!a-c]*", "cat"));
+		assert!(matches("\\*literal", "*literal"));
+		assert!(!matches("\\*literal", "not literal"));
+
+		// A trailing slash only matches directories, and what's inside them
+		let pattern = Pattern::new("build/").unwrap();
+		assert!(pattern.matches(Path::new("build"), true));
+		assert!(!pattern.matches(Path::new("build"), false));
+		assert!(pattern.matches(Path::new("src/build/out.o"), false));
+
+		for bad in &["", "/", "[abc", "trailing\\", "[a/b]"] {
+			assert!(Pattern::new(bad).is_err(), "{} was accepted", bad);
+		}
+	}
+
+	#[test]
+	fn path_filter_test() {
+		let mut filter = PathFilter::new();
+		assert!(filter.allows(Path::new("anything"), false));
+
+		filter.include.push(Pattern::new("src").unwrap());
+		filter.exclude.push(Pattern::new("*.bak").unwrap());
+		assert!(filter.allows(Path::new("src/main.rs"), false));
+		assert!(!filter.allows(Path::new("src/main.rs.bak"), false));
+		assert!(!filter.allows(Path::new("README.md"), false));
+	}
+
+	#[test]
+	fn ignore_rules_test() {
+		let outer = IgnoreRules::parse(Path::new(""), "# Build output\ntarget/\n*.log\n!keep.log\n\n/root_only.txt\n");
+		let inner = IgnoreRules::parse(Path::new("sub"), "!*.log\nsecret.txt\n");
+		let rules = [outer, inner];
+
+		assert!(is_ignored(&rules, Path::new("target"), true));
+		assert!(!is_ignored(&rules, Path::new("target"), false));
+		assert!(is_ignored(&rules, Path::new("debug.log"), false));
+		assert!(!is_ignored(&rules, Path::new("keep.log"), false));
+		assert!(is_ignored(&rules, Path::new("root_only.txt"), false));
+		assert!(!is_ignored(&rules, Path::new("other/root_only.txt"), false));
+
+		// The inner file only applies inside its own directory, and wins there
+		assert!(!is_ignored(&rules, Path::new("sub/debug.log"), false));
+		assert!(is_ignored(&rules, Path::new("sub/secret.txt"), false));
+		assert!(!is_ignored(&rules, Path::new("secret.txt"), false));
+	}
+}
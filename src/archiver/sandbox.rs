@@ -0,0 +1,378 @@
+//! Creates the files, directories and links of extracted entries, without ever letting them land outside of the
+//! directory they're extracted into. Paths are only allowed to be plain relative paths, and on Unix every directory
+//! along the way is opened with `O_NOFOLLOW`, so a symlink (whether it came from the archive or was swapped in
+//! while extracting) can't redirect a write somewhere else.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path};
+
+use archiver::ExtractOptions;
+use archiver::metadata::Metadata;
+
+/// A directory that entries are extracted into. Every path given to it is relative to that directory
+pub struct Sandbox {
+	#[cfg(unix)]
+	root: File, // Held open so the directory can't be swapped out from under us
+	#[cfg(not(unix))]
+	root: std::path::PathBuf
+}
+
+/// Splits `path` into the names of its components, refusing anything that could lead outside of the sandbox
+fn components(path: &Path) -> io::Result<Vec<&OsStr>> {
+	let mut names = Vec::new();
+	for component in path.components() {
+		match component {
+			Component::Normal(name) => names.push(name),
+			Component::CurDir => {},
+			_ => return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+				format!("\"{}\" leads outside of the extraction directory", path.display())))
+		}
+	}
+
+	if names.is_empty() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't extract to an empty path"));
+	}
+	Ok(names)
+}
+
+#[cfg(unix)]
+impl Sandbox {
+	/// Opens `root` to extract into, it has to exist already. `root` itself is trusted, so symlinks in it are followed
+	pub fn open(root: &Path) -> io::Result<Sandbox> {
+		use std::os::unix::fs::OpenOptionsExt;
+
+		let root = std::fs::OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC).open(root)?;
+		Ok(Sandbox { root })
+	}
+
+	/// Creates a file at `path`, replacing anything but a directory that's already there
+	pub fn create_file(&self, path: &Path) -> io::Result<File> {
+		use std::os::unix::io::{AsRawFd, FromRawFd};
+
+		let (dir, name) = self.parent(path)?;
+		unlink_existing(&dir, &name)?;
+		let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+		let fd = cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666 as libc::c_uint) })?;
+		Ok(unsafe { File::from_raw_fd(fd) })
+	}
+
+	/// Creates a directory at `path`, along with any missing directories before it. It's fine if it already exists
+	pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+		use std::os::unix::io::AsRawFd;
+
+		let (dir, name) = self.parent(path)?;
+		match cvt(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) }) {
+			Err(ref why) if why.kind() == io::ErrorKind::AlreadyExists => open_dir_at(&dir, &name).map(|_| ()),
+			result => result.map(|_| ())
+		}
+	}
+
+	/// Creates a symlink at `path` pointing to `target`. The target isn't checked, as nothing is ever written through it
+	pub fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+		use std::os::unix::io::AsRawFd;
+
+		let (dir, name) = self.parent(path)?;
+		unlink_existing(&dir, &name)?;
+		let target = c_string(target.as_os_str())?;
+		cvt(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) }).map(|_| ())
+	}
+
+	/// Creates a hard link at `path` to the file at `target`, which has to be inside the sandbox too
+	pub fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+		use std::os::unix::io::AsRawFd;
+
+		let (target_dir, target_name) = self.parent(target)?;
+		let (dir, name) = self.parent(path)?;
+		unlink_existing(&dir, &name)?;
+		cvt(unsafe { libc::linkat(target_dir.as_raw_fd(), target_name.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0) }).map(|_| ())
+	}
+
+	/// Applies `metadata` to whatever is at `path`, skipping whatever `options` says not to restore.
+	/// Ownership is restored first, as changing it can clear the setuid and setgid bits.
+	/// Symlinks are changed themselves rather than what they point to, and their permissions are left alone
+	pub fn restore_metadata(&self, path: &Path, metadata: &Metadata, options: &ExtractOptions) -> io::Result<()> {
+		use std::os::unix::io::{AsRawFd, FromRawFd};
+
+		let (dir, name) = self.parent(path)?;
+		let is_link = match stat_at(&dir, &name)? {
+			None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("\"{}\" doesn't exist", path.display()))),
+			Some(stat) => stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+		};
+
+		if options.restore_ownership {
+			let (uid, gid) = super::metadata::owner_ids(metadata);
+			cvt(unsafe { libc::fchownat(dir.as_raw_fd(), name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) })?;
+		}
+
+		// Symlink permissions can't be changed on most systems, and don't mean anything on the rest
+		if options.restore_permissions && !is_link {
+			let mask = if options.restore_special_bits { 0o7777 } else { 0o777 };
+			let mode = (metadata.mode & mask) as libc::mode_t;
+			let open = |access| cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), access | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC) });
+			// Files we can't read might still open for writing. Changing them by name would follow a symlink swapped in for them
+			let fd = match open(libc::O_RDONLY) {
+				Err(ref why) if why.kind() == io::ErrorKind::PermissionDenied => open(libc::O_WRONLY),
+				result => result
+			};
+			match fd {
+				Ok(fd) => {
+					let file = unsafe { File::from_raw_fd(fd) };
+					cvt(unsafe { libc::fchmod(file.as_raw_fd(), mode) })?;
+				},
+				// It's been swapped for a symlink since it was checked, and those have no permissions of their own
+				Err(ref why) if why.raw_os_error() == Some(libc::ELOOP) => {},
+				Err(why) => return Err(why)
+			}
+		}
+
+		if options.restore_times {
+			let to_timespec = |time: super::metadata::Timestamp| libc::timespec { tv_sec: time.secs as libc::time_t, tv_nsec: time.nanos as _ };
+			let times = [to_timespec(metadata.accessed), to_timespec(metadata.modified)];
+			cvt(unsafe { libc::utimensat(dir.as_raw_fd(), name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+		}
+
+		Ok(())
+	}
+
+	/// Opens the directory that `path` goes in, creating any that are missing, and returns it along with
+	/// the last component of `path`. Fails if any directory along the way is actually a symlink
+	fn parent(&self, path: &Path) -> io::Result<(File, std::ffi::CString)> {
+		use std::os::unix::io::AsRawFd;
+
+		let names = components(path)?;
+		let (name, parents) = names.split_last().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+		let mut dir = self.root.try_clone()?;
+		for parent in parents {
+			let parent = c_string(parent)?;
+			dir = match open_dir_at(&dir, &parent) {
+				Err(ref why) if why.kind() == io::ErrorKind::NotFound => {
+					match cvt(unsafe { libc::mkdirat(dir.as_raw_fd(), parent.as_ptr(), 0o777) }) {
+						Err(ref why) if why.kind() == io::ErrorKind::AlreadyExists => {},
+						result => { result?; }
+					}
+					open_dir_at(&dir, &parent)?
+				},
+				result => result?
+			};
+		}
+
+		Ok((dir, c_string(name)?))
+	}
+}
+
+#[cfg(unix)]
+fn c_string(string: &OsStr) -> io::Result<std::ffi::CString> {
+	use std::os::unix::ffi::OsStrExt;
+
+	std::ffi::CString::new(string.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Path contains a nul byte"))
+}
+
+/// Turns the -1 that libc functions return on failure into the error it stands for
+#[cfg(unix)]
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+	if result == -1 {
+		Err(io::Error::last_os_error())
+	} else {
+		Ok(result)
+	}
+}
+
+/// Opens the directory `name` inside `dir`, refusing to follow it if it's a symlink
+#[cfg(unix)]
+fn open_dir_at(dir: &File, name: &std::ffi::CStr) -> io::Result<File> {
+	use std::os::unix::io::{AsRawFd, FromRawFd};
+
+	let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+	match cvt(unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) }) {
+		Ok(fd) => Ok(unsafe { File::from_raw_fd(fd) }),
+		Err(ref why) if why.raw_os_error() == Some(libc::ELOOP) || why.raw_os_error() == Some(libc::ENOTDIR) =>
+			Err(io::Error::new(io::ErrorKind::PermissionDenied,
+				format!("\"{}\" is a symlink or file, so nothing can be extracted inside it", name.to_string_lossy()))),
+		Err(why) => Err(why)
+	}
+}
+
+/// Gets the status of `name` inside `dir` without following symlinks, or [`None`] if it doesn't exist
+#[cfg(unix)]
+fn stat_at(dir: &File, name: &std::ffi::CStr) -> io::Result<Option<libc::stat>> {
+	use std::os::unix::io::AsRawFd;
+
+	let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+	match cvt(unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) }) {
+		Ok(_) => Ok(Some(stat)),
+		Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+		Err(why) => Err(why)
+	}
+}
+
+/// Removes whatever is at `name` inside `dir`, so something else can be made there. Directories are left alone
+#[cfg(unix)]
+fn unlink_existing(dir: &File, name: &std::ffi::CStr) -> io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	match stat_at(dir, name)? {
+		Some(stat) if stat.st_mode & libc::S_IFMT == libc::S_IFDIR => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+			format!("\"{}\" is already a directory", name.to_string_lossy()))),
+		Some(_) => cvt(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) }).map(|_| ()),
+		None => Ok(())
+	}
+}
+
+/// Without `openat` this checks each directory along the way isn't a symlink before using it. That keeps out symlinks
+/// from the archive, but unlike on Unix it can't stop one from being swapped in between the check and the write
+#[cfg(not(unix))]
+impl Sandbox {
+	/// Opens `root` to extract into, it has to exist already. `root` itself is trusted, so symlinks in it are followed
+	pub fn open(root: &Path) -> io::Result<Sandbox> {
+		if !root.is_dir() {
+			return Err(io::Error::new(io::ErrorKind::NotFound, format!("\"{}\" isn't a directory", root.display())));
+		}
+		Ok(Sandbox { root: root.to_path_buf() })
+	}
+
+	/// Creates a file at `path`, replacing anything but a directory that's already there
+	pub fn create_file(&self, path: &Path) -> io::Result<File> {
+		let path = self.parent(path)?;
+		unlink_existing(&path)?;
+		std::fs::OpenOptions::new().write(true).create_new(true).open(path)
+	}
+
+	/// Creates a directory at `path`, along with any missing directories before it. It's fine if it already exists
+	pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+		let path = self.parent(path)?;
+		match std::fs::symlink_metadata(&path) {
+			Ok(ref metadata) if metadata.is_dir() => Ok(()),
+			Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("\"{}\" isn't a directory", path.display()))),
+			Err(_) => std::fs::create_dir(path)
+		}
+	}
+
+	/// Creates a symlink at `path` pointing to `target`. The target isn't checked, as nothing is ever written through it
+	pub fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+		let path = self.parent(path)?;
+		unlink_existing(&path)?;
+		create_symlink(target, &path)
+	}
+
+	/// Creates a hard link at `path` to the file at `target`, which has to be inside the sandbox too
+	pub fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+		let target = self.parent(target)?;
+		let path = self.parent(path)?;
+		unlink_existing(&path)?;
+		std::fs::hard_link(target, path)
+	}
+
+	/// Applies `metadata` to whatever is at `path`, skipping whatever `options` says not to restore.
+	/// Only the read only flag and timestamps can be restored outside of Unix, and symlinks are left alone
+	pub fn restore_metadata(&self, path: &Path, metadata: &Metadata, options: &ExtractOptions) -> io::Result<()> {
+		let path = self.parent(path)?;
+		if std::fs::symlink_metadata(&path)?.file_type().is_symlink() {
+			return Ok(());
+		}
+
+		if options.restore_times {
+			let to_time = |time: super::metadata::Timestamp| if time.secs >= 0 {
+				std::time::UNIX_EPOCH + std::time::Duration::new(time.secs as u64, time.nanos)
+			} else {
+				std::time::UNIX_EPOCH - std::time::Duration::new(time.secs.unsigned_abs(), 0) + std::time::Duration::new(0, time.nanos)
+			};
+			let times = std::fs::FileTimes::new().set_accessed(to_time(metadata.accessed)).set_modified(to_time(metadata.modified));
+			std::fs::OpenOptions::new().write(true).open(&path)?.set_times(times)?;
+		}
+
+		if options.restore_permissions {
+			let mut permissions = std::fs::metadata(&path)?.permissions();
+			permissions.set_readonly(metadata.mode & 0o222 == 0);
+			std::fs::set_permissions(&path, permissions)?;
+		}
+
+		Ok(())
+	}
+
+	/// Checks every directory before `path` is a real directory, creating any that are missing, and returns the full path
+	fn parent(&self, path: &Path) -> io::Result<std::path::PathBuf> {
+		let names = components(path)?;
+		let (name, parents) = names.split_last().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+		let mut dir = self.root.clone();
+		for parent in parents {
+			dir.push(parent);
+			match std::fs::symlink_metadata(&dir) {
+				Ok(ref metadata) if metadata.is_dir() => {},
+				Ok(_) => return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+					format!("\"{}\" is a symlink or file, so nothing can be extracted inside it", dir.display()))),
+				Err(_) => std::fs::create_dir(&dir)?
+			}
+		}
+
+		Ok(dir.join(name))
+	}
+}
+
+/// Removes whatever is at `path`, so something else can be made there. Directories are left alone
+#[cfg(not(unix))]
+fn unlink_existing(path: &Path) -> io::Result<()> {
+	match std::fs::symlink_metadata(path) {
+		Ok(ref metadata) if metadata.is_dir() => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+			format!("\"{}\" is already a directory", path.display()))),
+		Ok(_) => std::fs::remove_file(path),
+		Err(_) => Ok(())
+	}
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, path: &Path) -> io::Result<()> {
+	// Windows needs to know what kind of thing the link points to, so guess from the target if it exists
+	if path.parent().unwrap_or(Path::new("")).join(target).is_dir() {
+		std::os::windows::fs::symlink_dir(target, path)
+	} else {
+		std::os::windows::fs::symlink_file(target, path)
+	}
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, "Symlinks aren't supported on this platform"))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::prelude::*;
+
+	#[cfg(unix)]
+	#[test]
+	fn sandbox_escape_test() -> io::Result<()> {
+		std::fs::create_dir_all("sandbox_test/root")?;
+		let sandbox = Sandbox::open(Path::new("sandbox_test/root"))?;
+
+		for path in &["../outside.txt", "/tmp/outside.txt", "inside/../../outside.txt"] {
+			let why = sandbox.create_file(Path::new(path)).unwrap_err();
+			assert_eq!(why.kind(), io::ErrorKind::PermissionDenied, "{} wasn't refused", path);
+		}
+
+		// Directories along the way are created, but never followed if they're links
+		sandbox.create_file(Path::new("./a/b/c.txt"))?.write_all(b"Inside")?;
+		assert_eq!(std::fs::read("sandbox_test/root/a/b/c.txt")?, b"Inside");
+
+		sandbox.symlink(Path::new(".."), Path::new("up"))?;
+		assert!(sandbox.create_file(Path::new("up/outside.txt")).is_err());
+		assert!(sandbox.create_dir(Path::new("up/outside")).is_err());
+		assert!(!Path::new("sandbox_test/outside.txt").exists());
+		assert!(!Path::new("sandbox_test/outside").exists());
+
+		// Replacing a symlink replaces the link, rather than writing to where it points
+		std::fs::write("sandbox_test/target.txt", b"Untouched")?;
+		sandbox.symlink(Path::new("../../target.txt"), Path::new("link.txt"))?;
+		sandbox.create_file(Path::new("link.txt"))?.write_all(b"Replaced")?;
+		assert_eq!(std::fs::read("sandbox_test/target.txt")?, b"Untouched");
+		assert_eq!(std::fs::read("sandbox_test/root/link.txt")?, b"Replaced");
+
+		std::fs::remove_dir_all("sandbox_test")?;
+		Ok(())
+	}
+}
@@ -0,0 +1,158 @@
+//! Transforms make reversible changes to an entry's data as it's written into an archive, and undo them as it's read back out.
+//! Each one wraps a writer or reader, so it can keep whatever state it needs (dictionaries, cipher streams, hashers...)
+//! for the whole entry, and can produce more or less data than it's given. Transforms stack: the output of one
+//! can be written into another, see [`Chain`].
+
+use std::io;
+use std::io::prelude::*;
+
+/// A reversible change made to data as it passes through
+pub trait Transform {
+	/// Wraps `output` so that data written to the returned [`Encoder`] is transformed before it reaches `output`
+	fn encoder<'a>(&self, output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a>;
+
+	/// Wraps `input` so that data read from the returned reader has the transform undone. The decoder should
+	/// stop reading from `input` once it reaches the end of the transformed data
+	fn decoder<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a>;
+}
+
+/// The writing half of a [`Transform`]
+pub trait Encoder: Write {
+	/// Transforms anything still buffered and ends the transformed stream, then finishes the encoder being written to.
+	/// Data written after this won't be decodable
+	fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Lets any writer be the output at the bottom of a stack of encoders. Finishing it just flushes the writer
+pub struct PlainEncoder<W>(pub W);
+
+impl<W: Write> Write for PlainEncoder<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+	fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+impl<W: Write> Encoder for PlainEncoder<W> {
+	fn finish(mut self: Box<Self>) -> io::Result<()> { self.0.flush() }
+}
+
+/// Wraps `output` in each of `transforms`, so that data written to the result passes through them in order
+pub fn stack_encoders<'a>(transforms: &[&dyn Transform], output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a> {
+	transforms.iter().rev().fold(output, |output, transform| transform.encoder(output))
+}
+
+/// Wraps `input` in a decoder for each of `transforms`, undoing data that was written through
+/// [`stack_encoders`] with the same transforms
+pub fn stack_decoders<'a>(transforms: &[&dyn Transform], input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+	transforms.iter().rev().fold(input, |input, transform| transform.decoder(input))
+}
+
+/// Several transforms acting as one, data being encoded passes through them in the order they were added
+#[derive(Default)]
+pub struct Chain(Vec<Box<dyn Transform>>);
+
+impl Chain {
+	pub fn new() -> Chain {
+		Chain(Vec::new())
+	}
+
+	/// Adds `transform` to the end of the chain
+	pub fn push(&mut self, transform: Box<dyn Transform>) {
+		self.0.push(transform);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	fn transforms(&self) -> Vec<&dyn Transform> {
+		self.0.iter().map(|transform| &**transform).collect()
+	}
+}
+
+impl Transform for Chain {
+	fn encoder<'a>(&self, output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a> {
+		stack_encoders(&self.transforms(), output)
+	}
+
+	fn decoder<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+		stack_decoders(&self.transforms(), input)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use archiver::codec::Lz4;
+
+	/// XORs every byte with a key that changes as the data goes by, to check state carries across writes
+	struct RollingXor;
+
+	struct RollingXorEncoder<'a> { output: Box<dyn Encoder + 'a>, key: u8 }
+	struct RollingXorDecoder<'a> { input: Box<dyn Read + 'a>, key: u8 }
+
+	impl Transform for RollingXor {
+		fn encoder<'a>(&self, output: Box<dyn Encoder + 'a>) -> Box<dyn Encoder + 'a> {
+			Box::new(RollingXorEncoder { output, key: 0 })
+		}
+
+		fn decoder<'a>(&self, input: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+			Box::new(RollingXorDecoder { input, key: 0 })
+		}
+	}
+
+	impl<'a> Write for RollingXorEncoder<'a> {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let mut encoded = buf.to_vec();
+			for byte in &mut encoded {
+				*byte ^= self.key;
+				self.key = self.key.wrapping_add(1);
+			}
+			self.output.write_all(&encoded)?;
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> { self.output.flush() }
+	}
+
+	impl<'a> Encoder for RollingXorEncoder<'a> {
+		fn finish(self: Box<Self>) -> io::Result<()> { self.output.finish() }
+	}
+
+	impl<'a> Read for RollingXorDecoder<'a> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			let read = self.input.read(buf)?;
+			for byte in &mut buf[..read] {
+				*byte ^= self.key;
+				self.key = self.key.wrapping_add(1);
+			}
+			Ok(read)
+		}
+	}
+
+	#[test]
+	fn chain_round_trip_test() -> io::Result<()> {
+		let data: Vec<u8> = b"Stacked transforms ".iter().cycle().take(300_000).cloned().collect();
+
+		let mut chain = Chain::new();
+		chain.push(Box::new(Lz4));
+		chain.push(Box::new(RollingXor));
+
+		let mut encoded = Vec::new();
+		{
+			let mut encoder = chain.encoder(Box::new(PlainEncoder(&mut encoded)));
+			// Uneven writes, so the transforms have to carry state between them
+			for piece in data.chunks(7777) {
+				encoder.write_all(piece)?;
+			}
+			encoder.finish()?;
+		}
+		assert!(encoded.len() < data.len());
+
+		let mut decoded = Vec::new();
+		chain.decoder(Box::new(&encoded[..])).read_to_end(&mut decoded)?;
+		assert_eq!(decoded, data);
+
+		Ok(())
+	}
+}
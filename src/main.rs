@@ -1,25 +1,106 @@
 extern crate getopts; // Command line arguments
+extern crate lz4_flex; // Compression
+extern crate crc32c; // Checksums
+extern crate serde_json; // Tag files
+#[cfg(unix)]
+extern crate libc; // File metadata
 use getopts::Options;
 
+use std::collections::HashMap;
 use std::fs::File; // For files
-use std::path::PathBuf;
-use std::collections::HashMap; // For archive tags
+use std::io::{IsTerminal, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 pub mod archiver;
+use archiver::{EntryKind, FileEntry, Format, MpkError};
+use archiver::codec;
+use archiver::pattern::{self, PathFilter, Pattern};
+
+// Exit codes, following the BSD sysexits.h conventions
+const EXIT_USAGE: i32 = 64; // The command was used incorrectly
+const EXIT_DATAERR: i32 = 65; // The archive given was malformed
+const EXIT_NOINPUT: i32 = 66; // An input file didn't exist or wasn't readable
+const EXIT_CANTCREAT: i32 = 73; // An output file couldn't be created
+const EXIT_IOERR: i32 = 74; // Some other error occured while reading or writing
 
 fn main() {
 	let args: Vec<String> = std::env::args().collect();
 	let matches = match do_args(&args) {
-		Err(_) => return,
+		Err(code) => std::process::exit(code),
 		Ok(m) => m
 	};
 
+	let result = if matches.opt_present("v") {
+		print_versions(&matches.free)
+	} else {
+		run(&args, &matches)
+	};
+	if let Err(why) = result {
+		std::process::exit(exit_code(&why));
+	}
+}
+
+/// Picks the exit code that best describes `err`
+fn exit_code(err: &MpkError) -> i32 {
+	match err {
+		MpkError::Io(why) | MpkError::UnreadablePath(_, why) => match why.kind() {
+			std::io::ErrorKind::NotFound => EXIT_NOINPUT,
+			std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::AlreadyExists => EXIT_CANTCREAT,
+			_ => EXIT_IOERR
+		},
+		MpkError::TruncatedHeader
+		| MpkError::UnsupportedVersion(_)
+		| MpkError::NotAnArchive
+		| MpkError::InvalidUtf8Path(_)
+		| MpkError::MalformedTag(_)
+		| MpkError::PathEscape(_)
+		| MpkError::UnknownCodec(_)
+		| MpkError::ChecksumMismatch(_)
+		| MpkError::HeaderChecksumMismatch
+		| MpkError::UnknownEntryKind(_)
+		| MpkError::MissingLinkTarget(_)
+		| MpkError::HeaderTooLarge(_)
+		| MpkError::TooManyTags(_)
+		| MpkError::TooManyEntries(_)
+		| MpkError::PathTooLong(_)
+		| MpkError::EntryOutOfBounds(_)
+		| MpkError::DuplicateEntry(_) => EXIT_DATAERR,
+		MpkError::LinkLoop(_)
+		| MpkError::BrokenLink(_) => EXIT_NOINPUT,
+		MpkError::InvalidPattern(_) => EXIT_USAGE,
+		MpkError::TrailingIndex
+		| MpkError::FileChanged(_) => EXIT_IOERR
+	}
+}
+
+/// Runs the command given by `matches`. Commands that work on multiple archives keep going when
+/// one of them fails, and return the last error once they're done
+fn run(args: &[String], matches: &getopts::Matches) -> Result<(), MpkError> {
 	let command = &matches.free[0];
 	let absolute_paths = archiver::strings_to_paths(matches.free.clone()[1..].to_vec());
+	let mut result = Ok(());
 
-	let _compress = matches.opt_present("c");
+	let mut extract_options = archiver::ExtractOptions::default();
+	if matches.opt_present("no-same-owner") {
+		extract_options.restore_ownership = false;
+	}
+	if matches.opt_present("no-same-permissions") {
+		extract_options.restore_permissions = false;
+	}
+	if let Some(strip) = matches.opt_str("strip-components") {
+		extract_options.strip_components = match strip.parse() {
+			Err(_) => {
+				eprintln!("--strip-components takes a number of path components, not \"{}\"", strip);
+				std::process::exit(EXIT_USAGE);
+			},
+			Ok(strip) => strip
+		};
+	}
+	extract_options.flatten = matches.opt_present("flatten");
 
 	if command == "pack" || command == "p" { // Expand and pack absolute_paths
+		let mut builder = archive_builder(args, matches);
+		builder.set_trailing_index(matches.opt_present("trailing-index"));
 		let mut out_path = match matches.opt_str("o") {
 			None => match std::env::current_dir() {
 				Err(_) => PathBuf::from("Archive"),
@@ -31,145 +112,884 @@ fn main() {
 			Some(out) => PathBuf::from(&out)
 		};
 
-		out_path = out_path.with_extension("mpk");
+		// An output of - streams the archive to stdout, which can't seek so the index goes at the end
+		let to_stdout = out_path == Path::new("-");
+		let mut file = if to_stdout {
+			if std::io::stdout().is_terminal() {
+				eprintln!("Not writing an archive to a terminal, redirect the output somewhere else");
+				std::process::exit(EXIT_USAGE);
+			}
+			None
+		} else {
+			out_path = out_path.with_extension("mpk");
+			match File::create(&out_path) {
+				Err(why) => {
+					eprintln!("Unable to create {}: {}", out_path.display(), why);
+					return Err(why.into());
+				},
+				Ok(file) => Some(file),
+			}
+		};
+
+		for root in &absolute_paths {
+			if let Err(why) = builder.add_path(root) {
+				eprintln!("Unable to follow path tree with root \"{}\": {}", root.display(), why);
+				result = Err(why);
+			}
+		}
+
+		let finished = match file {
+			None => builder.finish_streaming(&mut std::io::BufWriter::new(std::io::stdout().lock())),
+			Some(ref mut file) => builder.finish(file)
+		};
+		if let Err(why) = finished {
+			eprintln!("Failed to pack archive \"{}\": {}", out_path.display(), why);
+			return Err(why);
+		}
+
+	} else if command == "add" || command == "a" || command == "append" {
+		// Adds the rest of the paths to the archive given by the first path
+		let archive_path = match absolute_paths.first() {
+			None => {
+				eprintln!("No archive given to add to");
+				std::process::exit(EXIT_USAGE);
+			},
+			Some(path) => path
+		};
+		let policy = match matches.opt_str("on-duplicate").as_deref() {
+			None | Some("error") => archiver::DuplicatePolicy::Error,
+			Some("replace") => archiver::DuplicatePolicy::Replace,
+			Some("keep") => archiver::DuplicatePolicy::KeepBoth,
+			Some(other) => {
+				eprintln!("Unknown duplicate policy \"{}\", it can be replace, keep or error", other);
+				std::process::exit(EXIT_USAGE);
+			}
+		};
+
+		let mut builder = archive_builder(args, matches);
+		let mut archive = match open_archive(archive_path, true).and_then(archiver::Archive::new) {
+			Err(why) => {
+				eprintln!("Failed to open archive \"{}\". {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(archive) => archive
+		};
+
+		for root in &absolute_paths[1..] {
+			if let Err(why) = builder.add_path(root) {
+				eprintln!("Unable to follow path tree with root \"{}\": {}", root.display(), why);
+				result = Err(why);
+			}
+		}
+
+		if let Err(why) = archive.append(builder, policy) {
+			match why {
+				MpkError::DuplicateEntry(_) => eprintln!("Nothing was added to \"{}\": {}. Use --on-duplicate to replace it or keep both",
+					archive_path.display(), why),
+				_ => eprintln!("Failed to add to archive \"{}\": {}", archive_path.display(), why)
+			}
+			return Err(why);
+		}
+
+	} else if command == "delete" || command == "d" {
+		// Removes entries matching the rest of the paths from the archive given by the first path
+		let archive_path = match absolute_paths.first() {
+			None => {
+				eprintln!("No archive given to delete from");
+				std::process::exit(EXIT_USAGE);
+			},
+			Some(path) => path
+		};
+		let patterns: Vec<Pattern> = matches.free[2..].iter().map(|path| match Pattern::anchored(path) {
+			Err(why) => {
+				eprintln!("{}", why);
+				std::process::exit(EXIT_USAGE);
+			},
+			Ok(pattern) => pattern
+		}).collect();
+		if patterns.is_empty() {
+			eprintln!("No paths given to delete from \"{}\"", archive_path.display());
+			std::process::exit(EXIT_USAGE);
+		}
+
+		let mut archive = match open_archive(archive_path, true).and_then(archiver::Archive::new) {
+			Err(why) => {
+				eprintln!("Failed to open archive \"{}\". {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(archive) => archive
+		};
+		let (indices, selected) = select_entries(archive_path, &archive.header.entries, &patterns, &path_filter(matches).exclude);
+		result = selected;
+		if let Err(why) = archive.delete(&indices) {
+			eprintln!("Failed to delete from \"{}\": {}", archive_path.display(), why);
+			return Err(why);
+		}
 
-		let mut file = match File::create(&out_path) {
-			Err(why) => panic!("Unable to create {}: {}", out_path.display(), why),
-			Ok(file) => file,
+	} else if command == "replace" || command == "r" {
+		// Swaps entries of the archive given by the first path for files, given as pairs of ENTRY FILE
+		let archive_path = match absolute_paths.first() {
+			None => {
+				eprintln!("No archive given to replace entries in");
+				std::process::exit(EXIT_USAGE);
+			},
+			Some(path) => path
 		};
+		let pairs = &absolute_paths[1..];
+		if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+			eprintln!("Entries to replace should be given as pairs of the path in the archive and the file to replace it with");
+			std::process::exit(EXIT_USAGE);
+		}
 
-		let tags = HashMap::new();
+		let mut builder = archive_builder(args, matches);
+		for pair in pairs.chunks(2) {
+			if let Err(why) = builder.add_file_as(&pair[1], &pair[0]) {
+				eprintln!("Unable to read \"{}\": {}", pair[1].display(), why);
+				return Err(why);
+			}
+		}
 
-		archiver::pack_archive(&mut file, &absolute_paths, tags);
+		let mut archive = match open_archive(archive_path, true).and_then(archiver::Archive::new) {
+			Err(why) => {
+				eprintln!("Failed to open archive \"{}\". {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(archive) => archive
+		};
+		if let Err(why) = archive.replace(builder) {
+			eprintln!("Nothing was replaced in \"{}\": {}", archive_path.display(), why);
+			return Err(why);
+		}
+
+	} else if command == "compact" {
+		// Reclaims the space left behind by deleted and replaced entries in each archive given
+		for archive_path in &absolute_paths {
+			let mut archive = match open_archive(archive_path, true).and_then(archiver::Archive::new) {
+				Err(why) => {
+					eprintln!("Failed to open archive \"{}\", skipping. {}", archive_path.display(), why);
+					result = Err(why);
+					continue;
+				},
+				Ok(archive) => archive
+			};
+
+			let old_len = match archive.file.seek(SeekFrom::End(0)) {
+				Err(why) => {
+					eprintln!("Failed to compact \"{}\": {}", archive_path.display(), why);
+					result = Err(why.into());
+					continue;
+				},
+				Ok(len) => len
+			};
+			let compacted = archive.compact().and_then(|len| {
+				archive.file.set_len(len)?;
+				Ok(len)
+			});
+			match compacted {
+				Err(why) => {
+					eprintln!("Failed to compact \"{}\": {}", archive_path.display(), why);
+					result = Err(why);
+				},
+				Ok(len) => println!("{}: reclaimed {} bytes", archive_path.display(), old_len.saturating_sub(len))
+			}
+		}
 
 	} else if command == "unpack" || command == "u" { // Unpack every archive in absolute_paths
+		let filter = path_filter(matches);
 		for archive_path in absolute_paths {
 			// This little mess determines the output path of the archive
 			// If it isn't specified, defaults to the name of the archive file
 			// If we can't get the archive name, just calls the folder "Archive"
 			let out_path = match matches.opt_str("o") {
+				None if archive_path == Path::new("-") => PathBuf::from("Archive"),
 				None => match archive_path.parent() {
 					None => PathBuf::from("Archive"),
 					Some(dir) => dir.join(match archive_path.file_stem() {
 						None => PathBuf::from("Archive"),
-						Some(stem) => PathBuf::from(stem) 
+						Some(stem) => PathBuf::from(stem)
 					})
 				},
 				Some(out) => PathBuf::from(&out)
 			};
 
-			// Try to open the archive file given to us
-			let archive_file = match File::open(&archive_path) {
-				Err(why) => { 
-					println!("Failed to open archive \"{}\", skipping. {}", archive_path.display(), why);
+			// Try to open the archive given to us
+			let mut archive = match read_archive(&archive_path) {
+				Err(why) => {
+					eprintln!("Unable to unpack archive \"{}\": {}", archive_path.display(), why);
+					result = Err(why);
 					continue;
 				},
-				Ok(f) => f
+				Ok(archive) => archive
 			};
 
-			archiver::unpack_archive(archive_file, &out_path).expect("Unable to unpack archive");
+			let (indices, selected) = select_entries(&archive_path, &archive.header.entries, &filter.include, &filter.exclude);
+			if let Err(why) = archiver::extract_entries(&mut archive, &indices, &out_path, &extract_options) {
+				eprintln!("Unable to unpack archive \"{}\": {}", archive_path.display(), why);
+				result = Err(why);
+			} else if selected.is_err() {
+				result = selected;
+			}
 		}
 
 	} else if command == "get" || command == "g" {
-		let archive_path = &absolute_paths[0];
-		let mut archive_file = match File::open(&archive_path) {
-			Err(why) => panic!("Failed to open archive \"{}\", skipping. {}", archive_path.display(), why),
-			Ok(f) => f
+		let archive_path = match absolute_paths.first() {
+			None => {
+				eprintln!("No archive given to get files from");
+				std::process::exit(EXIT_USAGE);
+			},
+			Some(path) => path
 		};
-		let header = archiver::read_header(&mut archive_file);
-
-		let mut archive = archiver::Archive {
-			file: archive_file,
-			header: header
+		let mut archive = match read_archive(archive_path) {
+			Err(why) => {
+				eprintln!("Unable to read archive \"{}\": {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(archive) => archive
 		};
 
 		let out_path = match matches.opt_str("o") {
+			None if archive_path == Path::new("-") => PathBuf::from("Archive"),
 			None => match archive_path.parent() {
 				None => PathBuf::from("Archive"),
 				Some(dir) => dir.join(match archive_path.file_stem() {
 					None => PathBuf::from("Archive"),
-					Some(stem) => PathBuf::from(stem) 
+					Some(stem) => PathBuf::from(stem)
 				})
 			},
 			Some(out) => PathBuf::from(&out)
 		};
 
-		std::fs::create_dir_all(&out_path).expect("Unable to create output directory");
-
-		for path in &absolute_paths[1..] {		
-			let mut extracted_file = match File::create(out_path.join(&path)) {
-				Err(why) => panic!("Failed to create file \"{}\", skipping. {}", archive_path.display(), why),
-				Ok(f) => f
-			};
+		// Paths given to get are matched from the root of the archive, and take in everything inside directories
+		let patterns: Vec<Pattern> = matches.free[2..].iter().map(|path| match Pattern::anchored(path) {
+			Err(why) => {
+				eprintln!("{}", why);
+				std::process::exit(EXIT_USAGE);
+			},
+			Ok(pattern) => pattern
+		}).collect();
+		if patterns.is_empty() {
+			eprintln!("No paths given to get from \"{}\"", archive_path.display());
+			std::process::exit(EXIT_USAGE);
+		}
 
-			match archiver::extract_from_archive(&mut path.clone(), &mut archive, &mut extracted_file, archiver::nothing) {
-				Err(why) => println!("Failed to extract target file \"{}\": {}", path.display(), why),
-				Ok(_) => ()
-			};
-			continue;
+		let (indices, selected) = select_entries(archive_path, &archive.header.entries, &patterns, &path_filter(matches).exclude);
+		result = selected;
+		if let Err(why) = archiver::extract_entries(&mut archive, &indices, &out_path, &extract_options) {
+			eprintln!("Failed to extract from \"{}\": {}", archive_path.display(), why);
+			result = Err(why);
 		}
-		
+
 	} else if command == "scan" || command == "s" {
 		// Prints the paths of every path in each archive given
+		let filter = path_filter(matches);
 		for archive_path in &absolute_paths {
-			// Try to open the archive file given to us
-			let mut archive_file = match File::open(archive_path) {
-				Err(why) => { 
-					println!("Failed to open archive \"{}\", skipping. {}", archive_path.display(), why);
+			let header = match read_archive(archive_path) {
+				Err(why) => {
+					eprintln!("Unable to read archive \"{}\", skipping. {}", archive_path.display(), why);
+					result = Err(why);
 					continue;
 				},
-				Ok(f) => f
+				Ok(archive) => archive.header
 			};
 
-			let header = archiver::read_header(&mut archive_file);
+			let entries: Vec<&FileEntry> = header.entries.iter()
+				.filter(|entry| filter.allows(&entry.path, entry.kind == EntryKind::Directory))
+				.collect();
 
-			for entry in &header.entries {
-				println!("{}", entry.path.display());
+			if matches.opt_present("json") {
+				// One line per archive, so the output of several archives can still be read a line at a time
+				let tags: serde_json::Map<String, serde_json::Value> = header.tags.iter()
+					.map(|(name, value)| (name.clone(), serde_json::Value::from(value.as_str())))
+					.collect();
+				let manifest = serde_json::json!({
+					"archive": archive_path.to_string_lossy(),
+					"version": header.version(),
+					"tags": tags,
+					"entries": entries.iter().map(|entry| entry_json(entry)).collect::<Vec<_>>()
+				});
+				println!("{}", manifest);
+			} else if matches.opt_present("l") {
+				println!("{:<10} {:>12} {:>12} {:>6}  {:<20}  {:<8}  PATH", "MODE", "SIZE", "STORED", "RATIO", "MODIFIED", "CHECKSUM");
+				for entry in entries {
+					print_long_entry(entry);
+				}
+			} else if matches.opt_present("null") {
+				for entry in entries {
+					print!("{}\0", entry.path.display());
+				}
+			} else {
+				for entry in entries {
+					println!("{}", entry.path.display());
+				}
+			}
+		}
+
+	} else if command == "tags" || command == "t" {
+		// Lists or edits the tags of the archive given by the first path
+		let archive_path = match absolute_paths.first() {
+			None => {
+				eprintln!("No archive given to read the tags of");
+				std::process::exit(EXIT_USAGE);
+			},
+			Some(path) => path
+		};
+		let action = matches.free.get(2).map(String::as_str).unwrap_or("list");
+		let names = matches.free.get(3..).unwrap_or(&[]);
+
+		let mut archive_file = match open_archive(archive_path, action == "set" || action == "delete") {
+			Err(why) => {
+				eprintln!("Failed to open archive \"{}\". {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(f) => f
+		};
+		let archive_start = archive_file.stream_position()?;
+		let mut tags = match archiver::read_index(&mut archive_file) {
+			Err(why) => {
+				eprintln!("Unable to read archive \"{}\": {}", archive_path.display(), why);
+				return Err(why);
+			},
+			Ok(header) => header.tags
+		};
+
+		match action {
+			"list" => {
+				let mut sorted: Vec<_> = tags.iter().collect();
+				sorted.sort();
+				for (name, value) in sorted {
+					println!("{}={}", name, value);
+				}
+			},
+			"get" => for name in names {
+				match tags.get(name) {
+					None => {
+						eprintln!("No tag \"{}\" in the archive", name);
+						result = Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such tag in the archive").into());
+					},
+					Some(value) => println!("{}", value)
+				}
+			},
+			"set" | "delete" => {
+				for name in names {
+					if action == "set" {
+						let (name, value) = parse_tag(name);
+						check_tag_name(&name);
+						tags.insert(name, value);
+					} else if tags.remove(name).is_none() {
+						eprintln!("No tag \"{}\" in the archive, skipping", name);
+					}
+				}
+
+				archive_file.seek(SeekFrom::Start(archive_start))?;
+				if let Err(why) = archiver::write_tags(&mut archive_file, tags) {
+					eprintln!("Unable to write the tags of \"{}\": {}", archive_path.display(), why);
+					return Err(why);
+				}
+			},
+			_ => {
+				eprintln!("Unknown tags action \"{}\", it can be list, get, set or delete", action);
+				std::process::exit(EXIT_USAGE);
+			}
+		}
+
+	} else if command == "verify" || command == "v" {
+		// Checks every entry of each archive given, without extracting anything
+		for archive_path in &absolute_paths {
+			let mut archive = match read_archive(archive_path) {
+				Err(why) => {
+					eprintln!("Unable to read archive \"{}\", skipping. {}", archive_path.display(), why);
+					result = Err(why);
+					continue;
+				},
+				Ok(archive) => archive
+			};
+
+			// Only entries with data can have a checksum, so directories, symlinks and empty files don't count
+			let unchecked = archive.header.entries.iter()
+				.filter(|entry| matches!(entry.kind, EntryKind::File | EntryKind::Hardlink(_)) && entry.size > 0 && entry.checksum.is_none())
+				.count();
+			let changed = archive.header.entries.iter().filter(|entry| entry.changed).count();
+			let bad_entries = match archiver::verify_archive(&mut archive) {
+				Err(why) => {
+					eprintln!("Unable to verify archive \"{}\", skipping. {}", archive_path.display(), why);
+					result = Err(why);
+					continue;
+				},
+				Ok(bad_entries) => bad_entries
+			};
+			for (path, why) in &bad_entries {
+				eprintln!("{}: bad entry \"{}\": {}", archive_path.display(), path.display(), why);
+			}
+
+			let total = archive.header.entries.len();
+			println!("{}: {} of {} entries OK", archive_path.display(), total - bad_entries.len(), total);
+			if unchecked > 0 {
+				println!("{}: {} {} no checksum, so {} only checked for readability", archive_path.display(), unchecked,
+					if unchecked == 1 { "entry has" } else { "entries have" }, if unchecked == 1 { "it was" } else { "they were" });
+			}
+			if changed > 0 {
+				println!("{}: {} {} changed while being packed, so might not match their files", archive_path.display(), changed,
+					if changed == 1 { "entry" } else { "entries" });
+			}
+			if let Some((_, why)) = bad_entries.into_iter().last() {
+				result = Err(why);
 			}
 		}
 
 	} else { // No pack or unpack flag given, print usage
-		print!("No commands given, use {} -h to see usage", args[0]);
-		return;
+		eprintln!("Unknown command \"{}\", use {} -h to see usage", command, args[0]);
+		std::process::exit(EXIT_USAGE);
+	}
+
+	result
+}
+
+/// The codec entries should be packed with. -c and --compress on its own pick lz4, while --compress=CODEC names one,
+/// exiting if it doesn't exist
+fn compression(matches: &getopts::Matches) -> Option<Box<dyn codec::Codec>> {
+	match matches.opt_str("compress") {
+		None if matches.opt_present("c") || matches.opt_present("compress") => Some(Box::new(codec::Lz4)),
+		None => None,
+		Some(name) => match codec::codec_by_name(&name) {
+			None => {
+				let names: Vec<_> = codec::builtin_codecs().iter().map(|codec| codec.name()).collect();
+				eprintln!("Unknown codec \"{}\", the available codecs are: {}", name, names.join(", "));
+				std::process::exit(EXIT_USAGE);
+			},
+			codec => codec
+		}
+	}
+}
+
+/// Sets up a builder with the codec, filters and tags given on the command line, exiting if any of them are invalid
+fn archive_builder(args: &[String], matches: &getopts::Matches) -> archiver::ArchiveBuilder<'static> {
+	let compression = compression(matches);
+
+	// Tags from a file come first, so ones given on the command line can override them
+	let mut tags = Vec::new();
+	if let Some(tags_path) = matches.opt_str("tags-from") {
+		match read_tags_file(Path::new(&tags_path)) {
+			Err(why) => {
+				eprintln!("Unable to read tags from \"{}\": {}", tags_path, why);
+				std::process::exit(EXIT_DATAERR);
+			},
+			Ok(file_tags) => tags.extend(file_tags)
+		}
+	}
+	for tag in matches.opt_strs("tag") {
+		tags.push(parse_tag(&tag));
+	}
+	for (name, _) in &tags {
+		check_tag_name(name);
+	}
+
+	let mut filter = path_filter(matches);
+	for exclude_path in matches.opt_strs("exclude-from") {
+		if let Err(why) = filter.add_exclude_file(Path::new(&exclude_path)) {
+			eprintln!("Unable to read exclude patterns from \"{}\": {}", exclude_path, why);
+			std::process::exit(exit_code(&why));
+		}
+	}
+
+	let on_change = match matches.opt_str("on-change").as_deref() {
+		None | Some("warn") => archiver::ChangePolicy::Warn,
+		Some("fail") => archiver::ChangePolicy::Fail,
+		Some(other) => {
+			eprintln!("Unknown change policy \"{}\", it can be warn or fail", other);
+			std::process::exit(EXIT_USAGE);
+		}
+	};
+
+	let mut builder = archiver::ArchiveBuilder::new();
+	builder.set_dereference(matches.opt_present("L"));
+	builder.set_on_change(on_change);
+	// Stdout might be where the archive is going, so warnings always go to stderr
+	builder.set_warning_handler(|warning| match warning {
+		MpkError::FileChanged(_) => eprintln!("Warning: {}, so it might not extract as any version of it", warning),
+		_ => eprintln!("Warning: {}, skipping it", warning)
+	});
+	builder.set_filter(filter);
+	if matches.opt_present("use-ignore-files") {
+		builder.set_ignore_files(&pattern::DEFAULT_IGNORE_FILES);
+	}
+	for (name, value) in &tags {
+		builder.set_tag(name, value);
+	}
+	if matches.opt_present("record-host") {
+		builder.record_host();
+	}
+	if matches.opt_present("record-command") {
+		builder.record_command(args);
+	}
+	if let Some(codec) = compression {
+		builder.set_codec(codec);
+	}
+	builder
+}
+
+/// Builds the filter given by the --include and --exclude options, exiting if a pattern is invalid
+fn path_filter(matches: &getopts::Matches) -> PathFilter {
+	let compile = |name| -> Vec<Pattern> {
+		matches.opt_strs(name).iter().map(|pattern| match Pattern::new(pattern) {
+			Err(why) => {
+				eprintln!("{}", why);
+				std::process::exit(EXIT_USAGE);
+			},
+			Ok(pattern) => pattern
+		}).collect()
+	};
+
+	let mut filter = PathFilter::new();
+	filter.include = compile("include");
+	filter.exclude = compile("exclude");
+	filter
+}
+
+/// Picks the entries that match any of `patterns` (or all of them if there are no patterns), leaving out those that
+/// match any of `exclude`. Patterns that didn't match anything are reported, and make the result an error
+fn select_entries(archive_path: &Path, entries: &[FileEntry], patterns: &[Pattern], exclude: &[Pattern]) -> (Vec<usize>, Result<(), MpkError>) {
+	let mut matched = vec![false; patterns.len()];
+	let mut indices = Vec::new();
+	for (index, entry) in entries.iter().enumerate() {
+		let is_dir = entry.kind == EntryKind::Directory;
+		let mut selected = patterns.is_empty();
+		for (pattern, matched) in patterns.iter().zip(matched.iter_mut()) {
+			if pattern.matches(&entry.path, is_dir) {
+				*matched = true;
+				selected = true;
+			}
+		}
+		if selected && !exclude.iter().any(|pattern| pattern.matches(&entry.path, is_dir)) {
+			indices.push(index);
+		}
+	}
+
+	let mut result = Ok(());
+	for (pattern, _) in patterns.iter().zip(matched).filter(|(_, matched)| !matched) {
+		eprintln!("Nothing in \"{}\" matches \"{}\"", archive_path.display(), pattern.as_str());
+		result = Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such file in the archive").into());
+	}
+	(indices, result)
+}
+
+/// Describes an entry for `scan --json`
+fn entry_json(entry: &FileEntry) -> serde_json::Value {
+	let (kind, target) = match entry.kind {
+		EntryKind::File => ("file", None),
+		EntryKind::Directory => ("directory", None),
+		EntryKind::Symlink(ref target) => ("symlink", Some(target.to_string_lossy())),
+		EntryKind::Hardlink(ref target) => ("hardlink", Some(target.to_string_lossy()))
+	};
+	let metadata = entry.metadata.as_ref();
+
+	serde_json::json!({
+		"path": entry.path.to_string_lossy(),
+		"kind": kind,
+		"target": target,
+		"size": entry.size,
+		"stored_size": entry.stored_size,
+		"codec": codec_name(entry.codec),
+		"checksum": entry.checksum.map(|checksum| format!("{:08x}", checksum)),
+		"mode": metadata.map(|metadata| metadata.mode),
+		"modified": metadata.map(|metadata| metadata.modified.to_string()),
+		"uid": metadata.map(|metadata| metadata.uid),
+		"gid": metadata.map(|metadata| metadata.gid),
+		"user": metadata.and_then(|metadata| metadata.user.clone()),
+		"group": metadata.and_then(|metadata| metadata.group.clone()),
+		"changed": entry.changed
+	})
+}
+
+/// Prints a row of the `scan -l` table
+fn print_long_entry(entry: &FileEntry) {
+	let type_char = match entry.kind {
+		EntryKind::File => '-',
+		EntryKind::Directory => 'd',
+		EntryKind::Symlink(_) => 'l',
+		EntryKind::Hardlink(_) => 'h'
+	};
+	let mode = match entry.metadata {
+		Some(ref metadata) => format!("{}{}", type_char, permissions_string(metadata.mode)),
+		None => format!("{}?????????", type_char)
+	};
+	let ratio = if entry.size == 0 {
+		String::from("-")
+	} else {
+		format!("{:.1}%", entry.stored_size as f64 * 100.0 / entry.size as f64)
+	};
+	let modified = entry.metadata.as_ref().map_or(String::from("-"), |metadata| metadata.modified.to_string());
+	let checksum = entry.checksum.map_or(String::from("-"), |checksum| format!("{:08x}", checksum));
+	let path = match entry.kind {
+		EntryKind::Symlink(ref target) => format!("{} -> {}", escape_path(&entry.path), escape_path(target)),
+		EntryKind::Hardlink(ref target) => format!("{} link to {}", escape_path(&entry.path), escape_path(target)),
+		_ => escape_path(&entry.path)
+	};
+	let path = if entry.changed { format!("{} (changed while packing)", path) } else { path };
+
+	println!("{:<10} {:>12} {:>12} {:>6}  {:<20}  {:<8}  {}", mode, entry.size, entry.stored_size, ratio, modified, checksum, path);
+}
+
+/// Formats Unix permission bits like ls does, such as rwxr-xr-x
+fn permissions_string(mode: u32) -> String {
+	let mut permissions: Vec<char> = "rwxrwxrwx".chars().enumerate()
+		.map(|(i, c)| if mode & (0o400 >> i) != 0 { c } else { '-' })
+		.collect();
+	// The setuid, setgid and sticky bits replace the execute bits they go with
+	for (bit, index, set) in &[(0o4000, 2, 's'), (0o2000, 5, 's'), (0o1000, 8, 't')] {
+		if mode & bit != 0 {
+			permissions[*index] = if permissions[*index] == 'x' { *set } else { set.to_ascii_uppercase() };
+		}
+	}
+	permissions.into_iter().collect()
+}
+
+/// Escapes control characters in a path, so a path with a newline in it can't break up the table
+fn escape_path(path: &Path) -> String {
+	path.to_string_lossy().chars().flat_map(|c| {
+		let escaped: Vec<char> = if c.is_control() { c.escape_default().collect() } else { vec![c] };
+		escaped
+	}).collect()
+}
+
+/// The name of the codec with the id `id`, or the id itself if it isn't a built in codec
+fn codec_name(id: u8) -> String {
+	codec::builtin_codecs().iter()
+		.find(|codec| codec.id() == id)
+		.map_or_else(|| id.to_string(), |codec| codec.name().to_string())
+}
+
+/// Splits a tag given as NAME=VALUE, exiting if there's no name
+fn parse_tag(tag: &str) -> (String, String) {
+	match tag.split_once('=') {
+		Some((name, value)) if !name.is_empty() => (name.to_string(), value.to_string()),
+		_ => {
+			eprintln!("Tag \"{}\" should be given as NAME=VALUE", tag);
+			std::process::exit(EXIT_USAGE);
+		}
+	}
+}
+
+/// Exits if `name` is one of the tags the archiver fills in itself
+fn check_tag_name(name: &str) {
+	if name.starts_with(archiver::RESERVED_TAG_PREFIX) {
+		eprintln!("Tag \"{}\" can't be set, names starting with \"{}\" are reserved for the archiver", name, archiver::RESERVED_TAG_PREFIX);
+		std::process::exit(EXIT_USAGE);
+	}
+}
+
+/// Reads tags from a JSON file holding a single object. Values can be strings, numbers or booleans
+fn read_tags_file(path: &Path) -> Result<HashMap<String, String>, String> {
+	let file = File::open(path).map_err(|why| why.to_string())?;
+	let json: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file)).map_err(|why| why.to_string())?;
+	let object = json.as_object().ok_or("Expected a JSON object of tag names and values")?;
+
+	let mut tags = HashMap::new();
+	for (name, value) in object {
+		let value = match value {
+			serde_json::Value::String(value) => value.clone(),
+			serde_json::Value::Number(_) | serde_json::Value::Bool(_) => value.to_string(),
+			_ => return Err(format!("The value of tag \"{}\" should be a string, number or boolean", name))
+		};
+		tags.insert(name.clone(), value);
 	}
+	Ok(tags)
 }
 
+/// Prints the version of the archiver, or if any archives are given the format version and origin of each
+fn print_versions(archive_paths: &[String]) -> Result<(), MpkError> {
+	if archive_paths.is_empty() {
+		println!("{} {} (archive format version {})", env!("CARGO_PKG_NAME"), archiver::VERSION, archiver::ARCHIVE_VERSION);
+		return Ok(());
+	}
+
+	let mut result = Ok(());
+	for archive_path in archive_paths {
+		let header = match open_archive(Path::new(archive_path), false).and_then(|mut file| archiver::read_index(&mut file)) {
+			Err(why) => {
+				eprintln!("Unable to read archive \"{}\", skipping. {}", archive_path, why);
+				result = Err(why);
+				continue;
+			},
+			Ok(header) => header
+		};
+
+		println!("{}: archive format version {}", archive_path, header.version());
+		let tag = |name| header.tags.get(name).map(String::as_str);
+		match (tag(archiver::TAG_WRITER), tag(archiver::TAG_WRITER_VERSION)) {
+			(None, None) => println!("  No record of what packed it"),
+			(writer, version) => println!("  Packed by {} {}", writer.unwrap_or("an unknown archiver"), version.unwrap_or("(unknown version)"))
+		}
+		for (name, label) in &[(archiver::TAG_CREATED, "Created"), (archiver::TAG_HOST, "Host"), (archiver::TAG_COMMAND, "Command")] {
+			if let Some(value) = tag(name) {
+				println!("  {}: {}", label, value);
+			}
+		}
+	}
+	result
+}
 
-fn do_args(args: &Vec<String>) -> Result<getopts::Matches, &str> {
+/// Opens the archive at `path`, making sure it looks like an archive before anything tries to read it.
+/// Archives with a trailing index that have been glued onto the end of another file are found from their footer,
+/// and the file is left at the start of the archive. It's opened for writing too if `writable` is set
+fn open_archive(path: &Path, writable: bool) -> Result<File, MpkError> {
+	let mut file = std::fs::OpenOptions::new().read(true).write(writable).open(path)?;
+	match archiver::detect_format(&mut file)? {
+		Format::Legacy(_) | Format::Mpk(_) => Ok(file),
+		Format::NotAnArchive => match archiver::read_footer(&mut file)? {
+			None => Err(MpkError::NotAnArchive),
+			Some(footer) => {
+				file.seek(SeekFrom::Start(footer.start))?;
+				Ok(file)
+			}
+		}
+	}
+}
+
+
+/// Anything an archive can be read from
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Opens the archive at `path` for reading, or reads it from stdin if `path` is -. Archives with their header at
+/// the start are read from stdin as they arrive, so their entries have to be read in the order their data comes.
+/// Ones with a trailing index can't be read until the end, so they're saved to a temporary file first
+fn read_archive(path: &Path) -> Result<archiver::Archive<Box<dyn ReadSeek>>, MpkError> {
+	if path != Path::new("-") {
+		return archiver::Archive::new(Box::new(open_archive(path, false)?));
+	}
+
+	let mut stdin = std::io::stdin().lock();
+	let mut prefix = Vec::new();
+	(&mut stdin).take(archiver::TRAILING_PREFIX_SIZE).read_to_end(&mut prefix)?;
+	let trailing = matches!(archiver::read_header(&mut &prefix[..]), Err(MpkError::TrailingIndex));
+	let mut input = std::io::Cursor::new(prefix).chain(stdin);
+	if trailing {
+		let mut file = temporary_file()?;
+		std::io::copy(&mut input, &mut file)?;
+		file.seek(SeekFrom::Start(0))?;
+		archiver::Archive::new(Box::new(file))
+	} else {
+		archiver::Archive::new_sequential(Box::new(archiver::SequentialReader::new(input)))
+	}
+}
+
+/// Creates a file in the system's temporary directory that's deleted once it's closed
+fn temporary_file() -> std::io::Result<File> {
+	let mut options = std::fs::OpenOptions::new();
+	options.read(true).write(true).create_new(true);
+	#[cfg(windows)]
+	{
+		use std::os::windows::fs::OpenOptionsExt;
+		const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x04000000;
+		options.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+	}
+
+	let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+	let path = std::env::temp_dir().join(format!("micropak-{}-{}.mpk", std::process::id(), nanos));
+	let file = options.open(&path)?;
+	// Elsewhere the file can be removed straight away, and lives on until it's closed
+	#[cfg(not(windows))]
+	std::fs::remove_file(&path)?;
+	Ok(file)
+}
+
+/// Parses the command line, on failure or when there's nothing to do returns the code to exit with
+fn do_args(args: &[String]) -> Result<getopts::Matches, i32> {
 	let mut opts = Options::new();
-	opts.optopt("o", "output", "Path to place the output", "PATH");
+	opts.optopt("o", "output", "Path to place the output, or - to pack to stdout", "PATH");
 	// opts.optopt("g", "get_from", "Unpack specific files from the archive specified after this flag", "ARCHIVE_PATH");
 	// opts.optflag("p", "pack", "Create an archive from the paths provided");
 	// opts.optflag("u", "unpack", "Unpack archives from the paths provided");
 	// opts.optflag("s", "scan", "Prints the paths of each item in the archive");
-	opts.optflag("c", "compress", "Enable experimental compression");
+	opts.optflag("c", "", "Compress entries with lz4 when packing");
+	// The codec has to be attached with =, so a bare --compress doesn't take the path after it
+	opts.optflagopt("", "compress", "Compress entries when packing. CODEC can be lz4 (the default) or store", "CODEC");
+	opts.optflag("", "trailing-index", "Put the index at the end of packed archives, so they can be written in one pass and glued onto other files");
+	opts.optflag("L", "dereference", "Store the files that symlinks point to when packing, rather than the links themselves");
+	opts.optflag("", "no-same-owner", "Don't give extracted files back to their original owners. This is the default unless running as root");
+	opts.optflag("", "no-same-permissions", "Don't restore the permissions of extracted files");
+	opts.optopt("", "strip-components", "Take N leading components off the path of each extracted entry, skipping entries with no more than that", "N");
+	opts.optflag("", "flatten", "Extract every file straight into the output directory, without the directories they were in");
+	opts.optopt("", "on-change", "What pack and add do when a file changes while it's being packed: warn (the default) and flag its entry, or fail", "warn|fail");
+	opts.optopt("", "on-duplicate", "What add does with paths that are already in the archive: replace them, keep both, or error (the default)", "replace|keep|error");
+	opts.optmulti("", "tag", "Add a tag to packed archives, can be given more than once", "NAME=VALUE");
+	opts.optopt("", "tags-from", "Add the tags in a JSON object to packed archives", "FILE");
+	opts.optmulti("", "include", "Only pack, scan or unpack paths that match PATTERN, can be given more than once", "PATTERN");
+	opts.optmulti("", "exclude", "Skip paths that match PATTERN when packing, scanning or extracting, can be given more than once", "PATTERN");
+	opts.optmulti("", "exclude-from", "Skip paths that match any of the patterns in FILE when packing, one per line", "FILE");
+	opts.optflag("", "use-ignore-files", "Skip paths ignored by .gitignore and .mpkignore files found while packing");
+	opts.optflag("l", "long", "Show the size, stored size, compression ratio, permissions, modification time and checksum of each entry when scanning");
+	opts.optflag("", "json", "Print a JSON manifest of each archive when scanning, with its version, tags and entries");
+	opts.optflag("0", "null", "End each path with a NUL byte rather than a newline when scanning");
+	opts.optflag("", "record-host", "Record the name of this machine in the tags of packed archives");
+	opts.optflag("", "record-command", "Record the command line in the tags of packed archives");
 	opts.optflag("h", "help", "Print this message");
-	opts.optflag("v", "version", "Print the version of this archiver. If a file is specified, print the version it was packed with");
+	opts.optflag("v", "version", "Print the version of this archiver. If archives are given, print the format version and the archiver they were packed with");
 	let matches = match opts.parse(&args[1..]) {
-        Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+		Ok(m) => { m }
+		Err(f) => {
+			eprintln!("{}, use {} -h to see usage", f, args[0]);
+			return Err(EXIT_USAGE);
+		}
 	};
 
 	let help_msg = format!(
 "Usage: {} COMMAND PATH1 PATH2 ... [options]
-		
+
+Archives are read from stdin when their path is -, for unpack, get, scan and verify
+
 Commands:
 pack | p: Create an archive from the paths provided
+add | a: Add the paths provided to the archive specified by the first path given, without rewriting what's already in it.
+    Paths already in the archive are handled according to --on-duplicate
+delete | d: Remove entries from the archive specified by the first path given, matched like the paths given to get.
+    Their data stays in the archive until it's compacted
+replace | r: Swap entries of the archive specified by the first path given for files, given in pairs:
+    replace ARCHIVE ENTRY FILE [ENTRY FILE]...
+compact: Reclaim the space left behind by deleted and replaced entries in the archives provided
 unpack | u: Unpack archives from the paths provided
-get | g: Unpack specific files from the archive specified by the first path given
-scan | s: Prints the paths of each item in the archive\n"
+get | g: Unpack specific files from the archive specified by the first path given. Paths can be directories or globs
+    like assets/**/*.png, which are matched from the root of the archive
+scan | s: Prints the paths of each item in the archive, which can be filtered with --include and --exclude
+tags | t: Lists or edits the tags of the archive specified by the first path given:
+    tags ARCHIVE [list | get NAME... | set NAME=VALUE... | delete NAME...]
+verify | v: Checks every entry of the archives provided against their checksums, without extracting anything\n"
 	, args[0]);
-	
+
 	if matches.opt_present("h") {
 		print!("{}", opts.usage(&help_msg));
-		return Err("Help message");
+		return Err(0);
 	}
 
-	if matches.free.is_empty() {
+	if matches.free.is_empty() && !matches.opt_present("v") {
 		print!("{}", opts.usage(&help_msg));
-		return Err("No command");
+		return Err(EXIT_USAGE);
 	}
 
 	Ok(matches)
 }
 
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(command_line: &str) -> getopts::Matches {
+		let args: Vec<String> = command_line.split(' ').map(String::from).collect();
+		do_args(&args).unwrap_or_else(|code| panic!("\"{}\" exited with {}", command_line, code))
+	}
+
+	#[test]
+	fn compress_usage_test() {
+		// Neither -c nor a bare --compress takes a value, so they can go anywhere without swallowing the path after them
+		for command_line in ["mpk pack -c src -o x", "mpk pack src -c -o x", "mpk pack --compress src", "mpk pack src --compress=lz4"] {
+			let matches = parse(command_line);
+			assert_eq!(matches.free, ["pack", "src"]);
+			assert_eq!(compression(&matches).map(|codec| codec.name()), Some("lz4"));
+		}
+
+		let matches = parse("mpk pack --compress=store src");
+		assert_eq!(matches.free, ["pack", "src"]);
+		assert_eq!(compression(&matches).map(|codec| codec.name()), Some("store"));
+		assert!(compression(&parse("mpk pack src")).is_none());
+	}
+}